@@ -0,0 +1,68 @@
+const { Worker } = require("worker_threads");
+const path = require("path");
+const { DecodedSignature } = require("./node/shazamio-core.js");
+
+/**
+ * Sends `request` to a new worker thread, resolving with the converted result of its last message.
+ */
+function runWorker(bytes, request, options, convert) {
+	const { onProgress, signal, transfer = false, track, hint, tolerant, downmix, sampleRate, resampleQuality, preprocess, normalize, dither, silence } = options;
+	return new Promise((resolve, reject) => {
+		if (signal?.aborted) return reject(signal.reason ?? new Error("Decoding was cancelled"));
+
+		const worker = new Worker(path.join(__dirname, "worker.js"));
+		const onAbort = () => {
+			worker.terminate();
+			reject(signal.reason ?? new Error("Decoding was cancelled"));
+		};
+		const settle = () => {
+			signal?.removeEventListener("abort", onAbort);
+			worker.terminate();
+		};
+		signal?.addEventListener("abort", onAbort, { once: true });
+
+		worker.on("message", (message) => {
+			if (message.type === "progress") return onProgress?.(message.progress);
+			settle();
+			if (message.type === "error") return reject(new Error(message.error));
+			resolve(convert(message));
+		});
+		worker.on("error", (error) => {
+			settle();
+			reject(error);
+		});
+
+		// Transferring detaches the caller's buffer, so by default (and for views into a larger buffer, like pooled Buffers) a copy is sent
+		const whole = bytes.byteOffset === 0 && bytes.byteLength === bytes.buffer.byteLength;
+		const input = transfer && whole ? bytes : new Uint8Array(bytes);
+		worker.postMessage({ ...request, bytes: input, track, hint, tolerant, downmix, sampleRate, resampleQuality, preprocess, normalize, dither, silence, progress: onProgress !== undefined }, [input.buffer]);
+	});
+}
+
+/**
+ * Runs `recognizeBytes` on a worker thread, resolving once the signatures are ready.
+ * @param {Uint8Array} bytes Bytes of the song file
+ * @param {number | "auto"} [offset] When to start sampling from in seconds, or "auto" for the best windows
+ * @param {number} [seconds] Seconds to sample from offset
+ * @param {{ onProgress?: (progress: object) => void, signal?: AbortSignal, transfer?: boolean, track?: number | string | { id: number }, hint?: string, tolerant?: boolean, downmix?: string | number, sampleRate?: number, resampleQuality?: "fast" | "balanced" | "best", preprocess?: object, normalize?: "none" | "peak" | "rms" | "agc", dither?: boolean, silence?: object }} [options]
+ * @returns {Promise<DecodedSignature[]>}
+ */
+function recognizeBytesAsync(bytes, offset, seconds, options = {}) {
+	return runWorker(bytes, { offset, seconds }, options, ({ signatures }) => signatures.map((signature) => DecodedSignature.fromBytes(signature)));
+}
+
+/**
+ * Runs `recognizeBestWindows` on a worker thread, resolving once the scored signatures are ready.
+ * @param {Uint8Array} bytes Bytes of the song file
+ * @param {number} [count] How many windows to return at most
+ * @param {number} [seconds] Length of each window in seconds
+ * @param {object} [options] Same as `recognizeBytesAsync`
+ * @returns {Promise<{ signature: DecodedSignature, score: number, startMs: number, loudnessDb: number, peaksPerSecond: number }[]>}
+ */
+function recognizeBestWindowsAsync(bytes, count, seconds, options = {}) {
+	return runWorker(bytes, { bestWindows: true, count, seconds }, options, ({ windows }) =>
+		windows.map((window) => ({ ...window, signature: DecodedSignature.fromBytes(window.signature) })),
+	);
+}
+
+module.exports = { recognizeBytesAsync, recognizeBestWindowsAsync };
@@ -0,0 +1,45 @@
+import type { DecodedSignature } from "./shazamio-core";
+
+export interface RecognizeProgress {
+	stage: "decoding" | "resampling" | "fingerprinting";
+	bytesRead: number;
+	totalBytes: number;
+	packetsDecoded: number;
+	percent?: number; // While decoding, only set when the duration of the song is known
+}
+
+export interface RecognizeAsyncOptions {
+	onProgress?: (progress: RecognizeProgress) => void;
+	signal?: AbortSignal; // Terminates the worker when aborted
+	transfer?: boolean; // Transfer `bytes` to the worker instead of copying them, detaching the caller's buffer
+	track?: number | string | { id: number }; // Audio track to decode, see `recognizeBytes`
+	hint?: string; // File name, extension or MIME type of the song file, see `recognizeBytes`
+	tolerant?: boolean; // Skip packets that fail to decode, see `recognizeBytes`
+	downmix?: "average" | "itu" | "mid" | "side" | "loudest" | "left" | "right" | "centre" | "center" | "lfe" | number; // How multichannel audio is mixed to mono, see `recognizeBytes`
+	sampleRate?: 8000 | 11025 | 16000 | 32000 | 44100 | 48000; // Rate signatures are generated at, see `recognizeBytes`
+	resampleQuality?: "fast" | "balanced" | "best"; // Speed and accuracy of resampling to `sampleRate`, see `recognizeBytes`
+	preprocess?: { dcRemoval?: boolean; highPassHz?: number; noiseGateDb?: number; preEmphasis?: number }; // Filters for noisy captures, see `recognizeBytes`
+	normalize?: "none" | "peak" | "rms" | "agc"; // Level adjustment before quantizing, see `recognizeBytes`
+	dither?: boolean; // Add TPDF dither when quantizing to 16 bits
+	silence?: { thresholdDb?: number; minPeaksPerBand?: number; drop?: boolean }; // Flag or drop silent signatures, see `recognizeBytes`
+}
+
+/**
+ * Runs `recognizeBytes` in a Web Worker, resolving once the signatures are ready.
+ * The module must be initialized on the calling thread first, to rebuild the signatures.
+ */
+export function recognizeBytesAsync(bytes: Uint8Array, offset?: number | "auto", seconds?: number, options?: RecognizeAsyncOptions): Promise<DecodedSignature[]>;
+
+export interface ScoredWindow {
+	signature: DecodedSignature;
+	score: number; // Between 0 and 1
+	startMs: number;
+	loudnessDb: number;
+	peaksPerSecond: number;
+}
+
+/**
+ * Runs `recognizeBestWindows` in a Web Worker, resolving once the scored signatures are ready.
+ * The module must be initialized on the calling thread first, to rebuild the signatures.
+ */
+export function recognizeBestWindowsAsync(bytes: Uint8Array, count?: number, seconds?: number, options?: RecognizeAsyncOptions): Promise<ScoredWindow[]>;
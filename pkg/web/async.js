@@ -0,0 +1,66 @@
+import { DecodedSignature } from "./shazamio-core.js";
+
+/**
+ * Sends `request` to a new Web Worker, resolving with the converted result of its last message.
+ */
+function runWorker(bytes, request, options, convert) {
+	const { onProgress, signal, transfer = false, track, hint, tolerant, downmix, sampleRate, resampleQuality, preprocess, normalize, dither, silence } = options;
+	return new Promise((resolve, reject) => {
+		if (signal?.aborted) return reject(signal.reason ?? new Error("Decoding was cancelled"));
+
+		const worker = new Worker(new URL("./worker.js", import.meta.url), { type: "module" });
+		const onAbort = () => {
+			worker.terminate();
+			reject(signal.reason ?? new Error("Decoding was cancelled"));
+		};
+		const settle = () => {
+			signal?.removeEventListener("abort", onAbort);
+			worker.terminate();
+		};
+		signal?.addEventListener("abort", onAbort, { once: true });
+
+		worker.onmessage = ({ data: message }) => {
+			if (message.type === "progress") return onProgress?.(message.progress);
+			settle();
+			if (message.type === "error") return reject(new Error(message.error));
+			resolve(convert(message));
+		};
+		worker.onerror = (event) => {
+			settle();
+			reject(event.error ?? new Error(event.message));
+		};
+
+		// Transferring detaches the caller's buffer, so by default (and for views into a larger buffer) a copy is sent
+		const whole = bytes.byteOffset === 0 && bytes.byteLength === bytes.buffer.byteLength;
+		const input = transfer && whole ? bytes : new Uint8Array(bytes);
+		worker.postMessage({ ...request, bytes: input, track, hint, tolerant, downmix, sampleRate, resampleQuality, preprocess, normalize, dither, silence, progress: onProgress !== undefined }, [input.buffer]);
+	});
+}
+
+/**
+ * Runs `recognizeBytes` in a Web Worker, resolving once the signatures are ready.
+ * The module must be initialized on the calling thread first, to rebuild the signatures.
+ * @param {Uint8Array} bytes Bytes of the song file
+ * @param {number | "auto"} [offset] When to start sampling from in seconds, or "auto" for the best windows
+ * @param {number} [seconds] Seconds to sample from offset
+ * @param {{ onProgress?: (progress: object) => void, signal?: AbortSignal, transfer?: boolean, track?: number | string | { id: number }, hint?: string, tolerant?: boolean, downmix?: string | number, sampleRate?: number, resampleQuality?: "fast" | "balanced" | "best", preprocess?: object, normalize?: "none" | "peak" | "rms" | "agc", dither?: boolean, silence?: object }} [options]
+ * @returns {Promise<DecodedSignature[]>}
+ */
+export function recognizeBytesAsync(bytes, offset, seconds, options = {}) {
+	return runWorker(bytes, { offset, seconds }, options, ({ signatures }) => signatures.map((signature) => DecodedSignature.fromBytes(signature)));
+}
+
+/**
+ * Runs `recognizeBestWindows` in a Web Worker, resolving once the scored signatures are ready.
+ * The module must be initialized on the calling thread first, to rebuild the signatures.
+ * @param {Uint8Array} bytes Bytes of the song file
+ * @param {number} [count] How many windows to return at most
+ * @param {number} [seconds] Length of each window in seconds
+ * @param {object} [options] Same as `recognizeBytesAsync`
+ * @returns {Promise<{ signature: DecodedSignature, score: number, startMs: number, loudnessDb: number, peaksPerSecond: number }[]>}
+ */
+export function recognizeBestWindowsAsync(bytes, count, seconds, options = {}) {
+	return runWorker(bytes, { bestWindows: true, count, seconds }, options, ({ windows }) =>
+		windows.map((window) => ({ ...window, signature: DecodedSignature.fromBytes(window.signature) })),
+	);
+}
@@ -0,0 +1,30 @@
+import initShazamio, { recognizeBytes, recognizeBestWindows } from "./shazamio-core.js";
+
+const ready = initShazamio();
+
+const signatureBytes = (signature) => {
+	const bytes = signature.toBytes();
+	signature.free();
+	return bytes;
+};
+
+self.onmessage = async ({ data: { bestWindows, bytes, offset, count, seconds, track, hint, tolerant, downmix, sampleRate, resampleQuality, preprocess, normalize, dither, silence, progress } }) => {
+	try {
+		await ready;
+		const onProgress = progress ? (progress) => self.postMessage({ type: "progress", progress }) : undefined;
+		const options = { onProgress, track, hint, tolerant, downmix, sampleRate, resampleQuality, preprocess, normalize, dither, silence };
+		if (bestWindows) {
+			const windows = recognizeBestWindows(bytes, count, seconds, options).map((scored) => {
+				const window = { signature: signatureBytes(scored.signature), score: scored.score, startMs: scored.startMs, loudnessDb: scored.loudnessDb, peaksPerSecond: scored.peaksPerSecond };
+				scored.free();
+				return window;
+			});
+			self.postMessage({ type: "windows", windows }, windows.map((window) => window.signature.buffer));
+			return;
+		}
+		const signatures = recognizeBytes(bytes, offset, seconds, options).map(signatureBytes);
+		self.postMessage({ type: "signatures", signatures }, signatures.map((signature) => signature.buffer));
+	} catch (error) {
+		self.postMessage({ type: "error", error: error instanceof Error ? error.message : String(error) });
+	}
+};
@@ -0,0 +1,28 @@
+const { parentPort } = require("worker_threads");
+const { recognizeBytes, recognizeBestWindows } = require("./node/shazamio-core.js");
+
+const signatureBytes = (signature) => {
+	const bytes = signature.toBytes();
+	signature.free();
+	return bytes;
+};
+
+parentPort.on("message", ({ bestWindows, bytes, offset, count, seconds, track, hint, tolerant, downmix, sampleRate, resampleQuality, preprocess, normalize, dither, silence, progress }) => {
+	try {
+		const onProgress = progress ? (progress) => parentPort.postMessage({ type: "progress", progress }) : undefined;
+		const options = { onProgress, track, hint, tolerant, downmix, sampleRate, resampleQuality, preprocess, normalize, dither, silence };
+		if (bestWindows) {
+			const windows = recognizeBestWindows(bytes, count, seconds, options).map((scored) => {
+				const window = { signature: signatureBytes(scored.signature), score: scored.score, startMs: scored.startMs, loudnessDb: scored.loudnessDb, peaksPerSecond: scored.peaksPerSecond };
+				scored.free();
+				return window;
+			});
+			parentPort.postMessage({ type: "windows", windows }, windows.map((window) => window.signature.buffer));
+			return;
+		}
+		const signatures = recognizeBytes(bytes, offset, seconds, options).map(signatureBytes);
+		parentPort.postMessage({ type: "signatures", signatures }, signatures.map((signature) => signature.buffer));
+	} catch (error) {
+		parentPort.postMessage({ type: "error", error: error instanceof Error ? error.message : String(error) });
+	}
+});
@@ -0,0 +1,126 @@
+use wasm_bindgen::prelude::*;
+
+use crate::fingerprinting::decoded_signature::DecodedSignature;
+use crate::fingerprinting::index::{FingerprintIndex, IndexMatch, IndexTrack};
+use crate::fingerprinting::samples_from_bytes::pcm_from_bytes;
+use crate::fingerprinting::signature_generator::SignatureGenerator;
+
+/// A catalogue of tracks to match signatures against offline, stored as
+/// bytes so it can be kept in IndexedDB or on disk between sessions.
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct Catalogue {
+    index: FingerprintIndex,
+}
+
+#[wasm_bindgen]
+impl Catalogue {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Catalogue {
+        Catalogue::default()
+    }
+
+    /// Loads a catalogue previously exported with `toBytes`.
+    #[wasm_bindgen(js_name = "fromBytes")]
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Catalogue, JsValue> {
+        match FingerprintIndex::from_bytes(bytes) {
+            Ok(index) => Ok(Catalogue { index }),
+            Err(e) => Err(JsValue::from_str(&e.to_string())),
+        }
+    }
+
+    /// Exports the catalogue, including tracks added since it was loaded.
+    #[wasm_bindgen(js_name = "toBytes")]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.index.to_bytes()
+    }
+
+    /// Fingerprints a whole song file and adds it to the catalogue, returning its track id.
+    #[wasm_bindgen(js_name = "addBytes")]
+    pub fn add_bytes(&mut self, name: &str, bytes: Vec<u8>) -> Result<u32, JsValue> {
+        self.add_song(name, bytes).map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// Adds a signature generated at 16 KHz to the catalogue, returning its track id.
+    #[wasm_bindgen(js_name = "addSignature")]
+    pub fn add_signature(&mut self, name: &str, signature: &mut DecodedSignature) -> Result<u32, JsValue> {
+        let duration_ms = signature.samplems();
+        self.index
+            .add_track(name, signature.frequency_band_to_sound_peaks(), duration_ms)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// Removes a track, returning whether it was in the catalogue.
+    pub fn remove(&mut self, track_id: u32) -> bool {
+        self.index.remove_track(track_id)
+    }
+
+    /// Drops removed tracks from the exported bytes.
+    pub fn compact(&mut self) {
+        self.index.compact();
+    }
+
+    /// Tracks in the catalogue.
+    pub fn tracks(&self) -> Vec<IndexTrack> {
+        self.index.tracks().into_iter().cloned().collect()
+    }
+
+    /// Finds the tracks matching a signature generated at 16 KHz, best match first.
+    pub fn query(&self, signature: &mut DecodedSignature) -> Result<Vec<IndexMatch>, JsValue> {
+        self.index
+            .query(signature.frequency_band_to_sound_peaks())
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+}
+
+impl Catalogue {
+    pub(crate) fn index(&self) -> &FingerprintIndex {
+        &self.index
+    }
+
+    /// Fingerprints every audio file of a folder, named after the file without its extension,
+    /// in file name order. Files that can't be decoded are skipped.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn from_folder(path: &std::path::Path) -> Result<Catalogue, Box<dyn std::error::Error>> {
+        let mut paths: Vec<_> = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<_, _>>()?;
+        paths.sort();
+
+        let mut catalogue = Catalogue::new();
+        for path in paths.into_iter().filter(|path| path.is_file()) {
+            let name = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+            // Anything that is not audio symphonia can decode, such as cover art
+            let _ = catalogue.add_song(&name, std::fs::read(&path)?);
+        }
+        Ok(catalogue)
+    }
+
+    fn add_song(&mut self, name: &str, bytes: Vec<u8>) -> Result<u32, Box<dyn std::error::Error>> {
+        let samples = pcm_from_bytes(bytes)?;
+        let frequency_band_to_sound_peaks = SignatureGenerator::frequency_band_to_sound_peaks(&samples);
+        let duration_ms = (samples.len() as u64 * 1000 / 16000) as u32;
+        self.index.add_track(name, &frequency_band_to_sound_peaks, duration_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprinting::test_audio::{noise, to_i16, wav};
+
+    #[test]
+    fn fingerprints_the_audio_files_of_a_folder() {
+        let folder = std::env::temp_dir().join(format!("shazamio-core-catalogue-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        std::fs::write(folder.join("b side.wav"), wav(16000, 1, &to_i16(&noise(2, 16000 * 5, 0.3)))).unwrap();
+        std::fs::write(folder.join("a side.wav"), wav(16000, 2, &to_i16(&noise(1, 16000 * 2 * 5, 0.3)))).unwrap();
+        std::fs::write(folder.join("notes.txt"), "not audio").unwrap();
+        std::fs::create_dir_all(folder.join("nested")).unwrap();
+
+        let catalogue = Catalogue::from_folder(&folder);
+        std::fs::remove_dir_all(&folder).unwrap();
+        let tracks: Vec<(String, u32)> = catalogue.unwrap().tracks().into_iter().map(|track| (track.name, track.duration_ms)).collect();
+        assert_eq!(tracks, [("a side".to_string(), 5000), ("b side".to_string(), 5000)]);
+    }
+}
@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use crate::fingerprinting::decoded_signature::DecodedSignature;
+use crate::fingerprinting::decoded_signature::DEFAULT_SAMPLE_RATE;
+use crate::fingerprinting::signature_generator::{peaks_sample_rate, FrequencyBand, FrequencyPeak};
+
+pub struct CompareOptions {
+    /// Largest time offset between the two signatures to consider, unbounded when `None`
+    pub max_offset_ms: Option<u32>,
+    /// Largest frequency difference for two peaks to be considered the same
+    pub frequency_tolerance_hz: f32,
+    /// Largest difference in FFT passes, after applying the offset, for two peaks to match
+    pub time_tolerance_passes: u32,
+}
+
+impl Default for CompareOptions {
+    fn default() -> Self {
+        CompareOptions {
+            max_offset_ms: None,
+            frequency_tolerance_hz: 10.0,
+            time_tolerance_passes: 1,
+        }
+    }
+}
+
+#[wasm_bindgen]
+pub struct SignatureComparison {
+    score: f32,
+    offset_ms: i32,
+    offset_fft_passes: i64,
+    matched_peaks: Vec<u32>,
+    total_peaks: Vec<u32>,
+}
+
+#[wasm_bindgen]
+impl SignatureComparison {
+    /// Fraction of peaks matched at the best offset, between 0 and 1
+    #[wasm_bindgen(getter)]
+    pub fn score(&self) -> f32 {
+        self.score
+    }
+
+    /// Offset of the second signature relative to the first, positive when the shared audio occurs later in the second
+    #[wasm_bindgen(getter, js_name = "offsetMs")]
+    pub fn offset_ms(&self) -> i32 {
+        self.offset_ms
+    }
+
+    /// Number of matched peaks for each frequency band, lowest band first
+    #[wasm_bindgen(getter, js_name = "matchedPeaks")]
+    pub fn matched_peaks(&self) -> Vec<u32> {
+        self.matched_peaks.clone()
+    }
+
+    /// Number of peaks each frequency band could have matched, the smaller count of the two signatures
+    #[wasm_bindgen(getter, js_name = "totalPeaks")]
+    pub fn total_peaks(&self) -> Vec<u32> {
+        self.total_peaks.clone()
+    }
+}
+
+impl SignatureComparison {
+    pub(crate) fn offset_fft_passes(&self) -> i64 {
+        self.offset_fft_passes
+    }
+
+    pub(crate) fn matched_peak_count(&self) -> u32 {
+        self.matched_peaks.iter().sum()
+    }
+}
+
+/// Peaks of one band as (frequency in Hz, fft pass number), sorted by frequency
+fn sorted_by_frequency(frequency_peaks: &[FrequencyPeak]) -> Vec<(f32, i64)> {
+    let mut sorted: Vec<(f32, i64)> = frequency_peaks
+        .iter()
+        .map(|peak| (peak.frequency_hz(), peak.fft_pass_number as i64))
+        .collect();
+    sorted.sort_by(|x, y| x.0.total_cmp(&y.0));
+    sorted
+}
+
+/// Peaks of one band as (fft pass number, frequency in Hz), sorted by pass number
+fn sorted_by_time(frequency_peaks: &[FrequencyPeak]) -> Vec<(i64, f32)> {
+    let mut sorted: Vec<(i64, f32)> = frequency_peaks
+        .iter()
+        .map(|peak| (peak.fft_pass_number as i64, peak.frequency_hz()))
+        .collect();
+    sorted.sort_by_key(|(fft_pass_number, _)| *fft_pass_number);
+    sorted
+}
+
+/// Calls `f` with the pass number of every peak in `sorted` between `first_pass` and `last_pass`
+/// within tolerance of `frequency_hz`
+fn for_each_between(sorted: &[(i64, f32)], first_pass: i64, last_pass: i64, frequency_hz: f32, tolerance_hz: f32, mut f: impl FnMut(i64)) {
+    let start = sorted.partition_point(|(fft_pass_number, _)| *fft_pass_number < first_pass);
+    for (fft_pass_number, hz) in &sorted[start..] {
+        if *fft_pass_number > last_pass {
+            break;
+        }
+        if (hz - frequency_hz).abs() <= tolerance_hz {
+            f(*fft_pass_number);
+        }
+    }
+}
+
+/// Calls `f` with the pass number of every peak in `sorted` within tolerance of `frequency_hz`
+fn for_each_near(sorted: &[(f32, i64)], frequency_hz: f32, tolerance_hz: f32, mut f: impl FnMut(i64)) {
+    let start = sorted.partition_point(|(hz, _)| *hz < frequency_hz - tolerance_hz);
+    for (hz, fft_pass_number) in &sorted[start..] {
+        if *hz > frequency_hz + tolerance_hz {
+            break;
+        }
+        f(*fft_pass_number);
+    }
+}
+
+/// Aligns the peaks of two signatures over every candidate time offset and scores the best one.
+pub fn compare_signatures(a: &mut DecodedSignature, b: &mut DecodedSignature, options: &CompareOptions) -> Result<SignatureComparison, Box<dyn Error>> {
+    compare_peaks(a.frequency_band_to_sound_peaks(), b.frequency_band_to_sound_peaks(), options)
+}
+
+/// Aligns two sets of peaks over every candidate time offset and scores the best one.
+/// Both must have been found at the same sample rate, as FFT passes and bins of other
+/// rates don't line up.
+pub fn compare_peaks(
+    a_peaks: &HashMap<FrequencyBand, Vec<FrequencyPeak>>,
+    b_peaks: &HashMap<FrequencyBand, Vec<FrequencyPeak>>,
+    options: &CompareOptions,
+) -> Result<SignatureComparison, Box<dyn Error>> {
+    let sample_rate_hz = match (peaks_sample_rate(a_peaks), peaks_sample_rate(b_peaks)) {
+        (Some(a_rate), Some(b_rate)) if a_rate != b_rate => {
+            return Err(format!("Can't compare signatures of different sample rates, {} Hz and {} Hz", a_rate, b_rate).into());
+        }
+        (a_rate, b_rate) => a_rate.or(b_rate).unwrap_or(DEFAULT_SAMPLE_RATE),
+    };
+    let max_offset_passes = options
+        .max_offset_ms
+        .map(|ms| ms as i64 * sample_rate_hz as i64 / 128 / 1000);
+
+    let bands: Vec<_> = FrequencyBand::ALL
+        .iter()
+        .map(|band| {
+            let a_band = a_peaks.get(band).map(Vec::as_slice).unwrap_or_default();
+            let b_band = b_peaks.get(band).map(Vec::as_slice).unwrap_or_default();
+            (a_band, sorted_by_frequency(b_band), sorted_by_time(b_band))
+        })
+        .collect();
+
+    // Vote for the offset implied by every pair of peaks with similar frequencies. With a
+    // maximum offset only the peaks of `b` within reach in time are visited, so the work
+    // grows with the offset range instead of with the length of both recordings.
+    let mut offset_votes: HashMap<i64, u32> = HashMap::new();
+    for (a_band, b_by_frequency, b_by_time) in &bands {
+        for peak in a_band.iter() {
+            let a_pass = peak.fft_pass_number as i64;
+            let vote = |b_pass: i64| *offset_votes.entry(b_pass - a_pass).or_default() += 1;
+            match max_offset_passes {
+                Some(max) => for_each_between(b_by_time, a_pass - max, a_pass + max, peak.frequency_hz(), options.frequency_tolerance_hz, vote),
+                None => for_each_near(b_by_frequency, peak.frequency_hz(), options.frequency_tolerance_hz, vote),
+            }
+        }
+    }
+
+    // Pick the offset with the most votes within the time tolerance, preferring the smallest offset on ties,
+    // then the negative one of two opposite offsets, so the result doesn't depend on the order of the map
+    let tolerance = options.time_tolerance_passes as i64;
+    let best_offset = offset_votes
+        .keys()
+        .map(|offset| {
+            let votes: u32 = (offset - tolerance..=offset + tolerance)
+                .filter_map(|o| offset_votes.get(&o))
+                .sum();
+            (*offset, votes)
+        })
+        .max_by(|x, y| x.1.cmp(&y.1).then(y.0.abs().cmp(&x.0.abs())).then(y.0.cmp(&x.0)))
+        .map(|(offset, _)| offset)
+        .unwrap_or(0);
+
+    let mut matched_peaks = vec![0u32; FrequencyBand::ALL.len()];
+    let mut total_peaks = vec![0u32; FrequencyBand::ALL.len()];
+    for (index, (a_band, _, b_by_time)) in bands.iter().enumerate() {
+        for peak in a_band.iter() {
+            let expected_pass = peak.fft_pass_number as i64 + best_offset;
+            let mut matched = false;
+            for_each_between(b_by_time, expected_pass - tolerance, expected_pass + tolerance, peak.frequency_hz(), options.frequency_tolerance_hz, |_| {
+                matched = true;
+            });
+            if matched {
+                matched_peaks[index] += 1;
+            }
+        }
+        total_peaks[index] = a_band.len().min(b_by_time.len()) as u32;
+    }
+
+    let total: u32 = total_peaks.iter().sum();
+    let matched: u32 = matched_peaks.iter().sum();
+
+    Ok(SignatureComparison {
+        score: if total == 0 { 0.0 } else { (matched as f32 / total as f32).min(1.0) },
+        offset_ms: (best_offset * 128 * 1000 / sample_rate_hz as i64) as i32,
+        offset_fft_passes: best_offset,
+        matched_peaks,
+        total_peaks,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprinting::signature_generator::window_peaks;
+
+    /// Peaks of one band at `sample_rate_hz`, every third FFT pass
+    fn peaks(sample_rate_hz: u32) -> HashMap<FrequencyBand, Vec<FrequencyPeak>> {
+        let frequency_peaks = (0..300)
+            .step_by(3)
+            .map(|fft_pass_number| FrequencyPeak {
+                fft_pass_number,
+                peak_magnitude: 8000,
+                corrected_peak_frequency_bin: ((64 + fft_pass_number % 200) * 64) as u16,
+                sample_rate_hz,
+            })
+            .collect();
+        HashMap::from([(FrequencyBand::_520_1450, frequency_peaks)])
+    }
+
+    /// `peaks` moved `shift` FFT passes later
+    fn shifted(peaks: &HashMap<FrequencyBand, Vec<FrequencyPeak>>, shift: u32) -> HashMap<FrequencyBand, Vec<FrequencyPeak>> {
+        window_peaks(peaks, 0, u32::MAX, shift as i64)
+    }
+
+    #[test]
+    fn recovers_a_known_offset() {
+        let a = peaks(16000);
+        let b = shifted(&a, 125);
+        for max_offset_ms in [None, Some(5000)] {
+            let comparison = compare_peaks(&a, &b, &CompareOptions { max_offset_ms, ..CompareOptions::default() }).unwrap();
+            assert_eq!(comparison.offset_fft_passes(), 125);
+            assert_eq!(comparison.offset_ms(), 1000);
+            assert_eq!(comparison.score(), 1.0);
+            let comparison = compare_peaks(&b, &a, &CompareOptions { max_offset_ms, ..CompareOptions::default() }).unwrap();
+            assert_eq!(comparison.offset_ms(), -1000);
+        }
+    }
+
+    #[test]
+    fn breaks_ties_between_opposite_offsets_the_same_way() {
+        // A single peak, found 20 passes earlier and 20 passes later in the other signature
+        let peak = |fft_pass_number| FrequencyPeak { fft_pass_number, peak_magnitude: 8000, corrected_peak_frequency_bin: 200 * 64, sample_rate_hz: 16000 };
+        let a = HashMap::from([(FrequencyBand::_520_1450, vec![peak(100)])]);
+        let b = HashMap::from([(FrequencyBand::_520_1450, vec![peak(80), peak(120)])]);
+        // Maps iterate in a different order every time they are created
+        for _ in 0..50 {
+            assert_eq!(compare_peaks(&a, &b, &CompareOptions::default()).unwrap().offset_fft_passes(), -20);
+        }
+    }
+
+    #[test]
+    fn rejects_peaks_of_different_sample_rates() {
+        let options = CompareOptions::default();
+        let error = compare_peaks(&peaks(16000), &peaks(8000), &options).err().unwrap();
+        assert!(error.to_string().contains("16000 Hz and 8000 Hz"));
+        assert_eq!(compare_peaks(&peaks(8000), &peaks(8000), &options).unwrap().score(), 1.0);
+        // Without peaks on one side there is nothing to disagree on
+        assert_eq!(compare_peaks(&HashMap::new(), &peaks(8000), &options).unwrap().score(), 0.0);
+    }
+}
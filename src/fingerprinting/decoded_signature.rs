@@ -1,41 +1,262 @@
 use base64::engine::general_purpose;
 use base64::Engine;
-use byteorder::{LittleEndian, WriteBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use crc32fast::Hasher;
-use wasm_bindgen::prelude::wasm_bindgen;
+use wasm_bindgen::prelude::*;
 
+use std::collections::HashMap;
 use std::error::Error;
-use std::io::{Cursor, Seek, SeekFrom, Write};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 
+use crate::fingerprinting::quality::{PcmStats, SignatureQuality};
 use crate::fingerprinting::resample::resample;
-use crate::fingerprinting::signature_generator::SignatureGenerator;
+use crate::fingerprinting::samples_from_bytes::DecodeReport;
+use crate::fingerprinting::signature_generator::{FrequencyBand, FrequencyPeak, SignatureGenerator};
 
 const DATA_URI_PREFIX: &str = "data:audio/vnd.shazam.sig;base64,";
 
+/// Sample rates a signature can be generated at, in the order of their ids in the signature header (from 1)
+pub const SAMPLE_RATES: [u32; 6] = [8000, 11025, 16000, 32000, 44100, 48000];
+
+/// Rate signatures are generated at unless asked otherwise
+pub const DEFAULT_SAMPLE_RATE: u32 = 16000;
+
+/// Returns `sample_rate_hz` when signatures can be generated at it, one of `SAMPLE_RATES`
+pub fn check_sample_rate(sample_rate_hz: u32) -> Result<u32, Box<dyn Error>> {
+    if !SAMPLE_RATES.contains(&sample_rate_hz) {
+        return Err(format!("Unsupported sample rate {} Hz, expected one of {:?}", sample_rate_hz, SAMPLE_RATES).into());
+    }
+    Ok(sample_rate_hz)
+}
+
 #[wasm_bindgen]
+#[derive(Clone)]
 pub struct DecodedSignature {
     sample_rate_hz: u32,
     orig_sample_rate_hz: u32,
     orig_channel_count: usize,
     f32_buffer: Vec<f32>,
     _i16_buffer: Vec<i16>,
+    _number_samples: Option<usize>,
+    _frequency_band_to_sound_peaks: Option<HashMap<FrequencyBand, Vec<FrequencyPeak>>>,
+    /// Level and clipping of the samples, measured before they are freed
+    _pcm_stats: Option<PcmStats>,
+    /// Whether the signature was judged silent by `mark_silence`
+    _silent: Option<bool>,
     uri: String,
 }
 
+/// When a signature counts as silent or too poor in peaks to be worth recognizing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SilenceOptions {
+    /// RMS level in dBFS below which the audio is silent
+    pub threshold_db: f32,
+    /// Fewest peaks the frequency bands must average for the signature to carry information
+    pub min_peaks_per_band: u32,
+    /// Leave silent signatures out instead of only flagging them
+    pub drop: bool,
+}
+
+impl Default for SilenceOptions {
+    fn default() -> Self {
+        SilenceOptions {
+            threshold_db: -60.0,
+            min_peaks_per_band: 5,
+            drop: false,
+        }
+    }
+}
+
 #[wasm_bindgen]
 impl DecodedSignature {
-    pub fn new(f32_buffer: Vec<f32>, orig_sample_rate_hz: u32, orig_channel_count: usize) -> DecodedSignature {
+    /// Creates a signature from interleaved samples, resampled to `sample_rate_hz` (one of `SAMPLE_RATES`, 16 KHz when unset)
+    /// once its peaks are needed.
+    pub fn new(f32_buffer: Vec<f32>, orig_sample_rate_hz: u32, orig_channel_count: usize, sample_rate_hz: Option<u32>) -> Result<DecodedSignature, JsValue> {
+        let sample_rate_hz = check_sample_rate(sample_rate_hz.unwrap_or(DEFAULT_SAMPLE_RATE)).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(DecodedSignature::from_samples(f32_buffer, orig_sample_rate_hz, orig_channel_count, sample_rate_hz))
+    }
+
+    /// Like the constructor, with a sample rate already checked.
+    pub(crate) fn from_samples(f32_buffer: Vec<f32>, orig_sample_rate_hz: u32, orig_channel_count: usize, sample_rate_hz: u32) -> DecodedSignature {
         DecodedSignature {
-            sample_rate_hz: 16000,
+            sample_rate_hz,
             orig_sample_rate_hz,
             orig_channel_count,
             _i16_buffer: Vec::new(),
+            _number_samples: None,
+            _frequency_band_to_sound_peaks: None,
+            _pcm_stats: None,
+            _silent: None,
             f32_buffer,
             uri: String::new(),
         }
     }
 
+    /// Creates a signature from mono samples already resampled to `sample_rate_hz`, one of `SAMPLE_RATES`.
+    pub(crate) fn from_pcm(i16_buffer: Vec<i16>, sample_rate_hz: u32) -> DecodedSignature {
+        DecodedSignature {
+            _i16_buffer: i16_buffer,
+            ..DecodedSignature::from_samples(Vec::new(), sample_rate_hz, 1, sample_rate_hz)
+        }
+    }
+
+    /// Parses a signature previously encoded as a `data:audio/vnd.shazam.sig` uri.
+    #[wasm_bindgen(js_name = "fromUri")]
+    pub fn from_uri(uri: &str) -> Result<DecodedSignature, JsValue> {
+        DecodedSignature::decode_from_uri(uri).map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// Parses a signature from the binary returned by `toBytes`.
+    #[wasm_bindgen(js_name = "fromBytes")]
+    pub fn from_bytes(bytes: &[u8]) -> Result<DecodedSignature, JsValue> {
+        DecodedSignature::decode_from_binary(bytes).map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// Signature in the binary format the uri wraps, which can be transferred between workers.
+    #[wasm_bindgen(js_name = "toBytes")]
+    pub fn to_bytes(&mut self) -> Vec<u8> {
+        self.encode_to_binary().unwrap_or_default()
+    }
+
+    /// Number of spectral peaks in the signature, across all frequency bands.
+    #[wasm_bindgen(getter, js_name = "peakCount")]
+    pub fn peak_count(&mut self) -> u32 {
+        self.frequency_band_to_sound_peaks().values().map(|frequency_peaks| frequency_peaks.len() as u32).sum()
+    }
+
+    /// Whether the audio is silent or holds too few peaks to be recognized, so it isn't worth sending.
+    #[wasm_bindgen(getter, js_name = "isSilent")]
+    pub fn is_silent(&mut self) -> bool {
+        match self._silent {
+            Some(silent) => silent,
+            None => self.detect_silence(&SilenceOptions::default()),
+        }
+    }
+
+    /// Peak density, band coverage, magnitude distribution and the silent and clipped share of
+    /// the audio, to decide whether a capture is worth sending or should be recorded for longer.
+    pub fn quality(&mut self) -> SignatureQuality {
+        self.generate_peaks(|_| true);
+        let number_samples = self.number_samples();
+        let frequency_band_to_sound_peaks = self._frequency_band_to_sound_peaks.as_ref().unwrap();
+        SignatureQuality::measure(frequency_band_to_sound_peaks, number_samples, self.sample_rate_hz, self._pcm_stats.as_ref())
+    }
+
+    /// Peaks of the signature as `[band, fftPassNumber, peakMagnitude, correctedPeakFrequencyBin]`
+    /// for every peak, ordered by band then time.
+    pub fn peaks(&mut self) -> Vec<u32> {
+        let mut sorted_iterator: Vec<_> = self.frequency_band_to_sound_peaks().iter().collect();
+        sorted_iterator.sort_by(|x, y| x.0.cmp(y.0));
+        sorted_iterator
+            .into_iter()
+            .flat_map(|(frequency_band, frequency_peaks)| {
+                frequency_peaks.iter().flat_map(move |frequency_peak| {
+                    [
+                        *frequency_band as u32,
+                        frequency_peak.fft_pass_number,
+                        frequency_peak.peak_magnitude as u32,
+                        frequency_peak.corrected_peak_frequency_bin as u32,
+                    ]
+                })
+            })
+            .collect()
+    }
+
+    pub(crate) fn decode_from_uri(uri: &str) -> Result<DecodedSignature, Box<dyn Error>> {
+        let data = uri.strip_prefix(DATA_URI_PREFIX).unwrap_or(uri);
+        DecodedSignature::decode_from_binary(&general_purpose::STANDARD.decode(data)?)
+    }
+
+    pub(crate) fn decode_from_binary(data: &[u8]) -> Result<DecodedSignature, Box<dyn Error>> {
+        let mut cursor = Cursor::new(data);
+
+        // See encode_to_binary for the layout of the header fields.
+        if data.len() < 56 || cursor.read_u32::<LittleEndian>()? != 0xcafe2580 {
+            return Err("Invalid signature header".into());
+        }
+        let crc32 = cursor.read_u32::<LittleEndian>()?;
+        let mut hasher = Hasher::new();
+        hasher.update(&data[8..]);
+        if hasher.finalize() != crc32 {
+            return Err("Invalid signature checksum".into());
+        }
+        let size_minus_header = cursor.read_u32::<LittleEndian>()? as usize;
+        let end = match size_minus_header.checked_add(48) {
+            Some(end) if end <= data.len() => end,
+            _ => return Err("Invalid signature header".into()),
+        };
+        if cursor.read_u32::<LittleEndian>()? != 0x94119c00 {
+            return Err("Invalid signature header".into());
+        }
+
+        cursor.seek(SeekFrom::Start(28))?;
+        let sample_rate_hz = match (cursor.read_u32::<LittleEndian>()? >> 27).checked_sub(1).and_then(|id| SAMPLE_RATES.get(id as usize)) {
+            Some(sample_rate_hz) => *sample_rate_hz,
+            None => return Err("Invalid sample rate in signature header".into()),
+        };
+
+        cursor.seek(SeekFrom::Start(40))?;
+        let number_samples = (cursor.read_u32::<LittleEndian>()? as usize)
+            .saturating_sub((sample_rate_hz as f32 * 0.24) as usize);
+
+        let mut frequency_band_to_sound_peaks: HashMap<FrequencyBand, Vec<FrequencyPeak>> = HashMap::new();
+
+        cursor.seek(SeekFrom::Start(56))?;
+        while (cursor.position() as usize) + 8 <= end {
+            let tag = cursor.read_u32::<LittleEndian>()?;
+            let size = cursor.read_u32::<LittleEndian>()? as usize;
+            // The size comes from the file, so check it before allocating anything
+            if size > end - cursor.position() as usize {
+                return Err("Invalid frequency band size in signature".into());
+            }
+            let mut peaks_buffer = vec![0u8; size];
+            cursor.read_exact(&mut peaks_buffer)?;
+            cursor.seek(SeekFrom::Current(((4 - size % 4) % 4) as i64))?;
+
+            let frequency_band = match tag.checked_sub(0x60030040).and_then(FrequencyBand::from_index) {
+                Some(frequency_band) => frequency_band,
+                None => continue,
+            };
+
+            let mut peaks_cursor = Cursor::new(peaks_buffer);
+            let mut fft_pass_number = 0;
+            let frequency_peaks = frequency_band_to_sound_peaks.entry(frequency_band).or_default();
+
+            while (peaks_cursor.position() as usize) < size {
+                let fft_pass_offset = peaks_cursor.read_u8()?;
+                if fft_pass_offset == 0xff {
+                    fft_pass_number = peaks_cursor.read_u32::<LittleEndian>()?;
+                    continue;
+                }
+                fft_pass_number = fft_pass_number
+                    .checked_add(fft_pass_offset as u32)
+                    .ok_or("Invalid FFT pass number in signature")?;
+
+                frequency_peaks.push(FrequencyPeak {
+                    fft_pass_number,
+                    peak_magnitude: peaks_cursor.read_u16::<LittleEndian>()?,
+                    corrected_peak_frequency_bin: peaks_cursor.read_u16::<LittleEndian>()?,
+                    sample_rate_hz,
+                });
+            }
+        }
+
+        Ok(DecodedSignature {
+            sample_rate_hz,
+            orig_sample_rate_hz: sample_rate_hz,
+            orig_channel_count: 1,
+            f32_buffer: Vec::new(),
+            _i16_buffer: Vec::new(),
+            _number_samples: Some(number_samples),
+            _frequency_band_to_sound_peaks: Some(frequency_band_to_sound_peaks),
+            _pcm_stats: None,
+            _silent: None,
+            uri: String::new(),
+        })
+    }
+
     fn encode_to_binary(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let number_samples = self.number_samples();
         let mut cursor = Cursor::new(vec![]);
 
         // Please see the RawSignatureHeader structure definition above for
@@ -49,14 +270,9 @@ impl DecodedSignature {
         cursor.write_u32::<LittleEndian>(0)?;
         cursor.write_u32::<LittleEndian>(0)?;
         cursor.write_u32::<LittleEndian>(
-            match self.sample_rate_hz {
-                8000 => 1,
-                11025 => 2,
-                16000 => 3,
-                32000 => 4,
-                44100 => 5,
-                48000 => 6,
-                _ => {
+            match SAMPLE_RATES.iter().position(|sample_rate_hz| *sample_rate_hz == self.sample_rate_hz) {
+                Some(index) => index as u32 + 1,
+                None => {
                     panic!("Invalid sample rate passed when encoding Shazam packet");
                 }
             } << 27,
@@ -64,15 +280,14 @@ impl DecodedSignature {
         cursor.write_u32::<LittleEndian>(0)?; // void2
         cursor.write_u32::<LittleEndian>(0)?;
         cursor.write_u32::<LittleEndian>(
-            self.number_samples() as u32 + (self.sample_rate_hz as f32 * 0.24) as u32,
+            number_samples as u32 + (self.sample_rate_hz as f32 * 0.24) as u32,
         )?; // number_samples_plus_divided_sample_rate
         cursor.write_u32::<LittleEndian>((15 << 19) + 0x40000)?; // fixed_value
 
         cursor.write_u32::<LittleEndian>(0x40000000)?;
         cursor.write_u32::<LittleEndian>(0)?; // size_minus_header - Will write later
 
-        let frequency_band_to_sound_peaks = SignatureGenerator::frequency_band_to_sound_peaks(&self.i16_buffer());
-        let mut sorted_iterator: Vec<_> = frequency_band_to_sound_peaks.iter().collect();
+        let mut sorted_iterator: Vec<_> = self.frequency_band_to_sound_peaks().iter().collect();
         sorted_iterator.sort_by(|x, y| x.0.cmp(y.0));
 
         for (frequency_band, frequency_peaks) in sorted_iterator {
@@ -127,7 +342,7 @@ impl DecodedSignature {
 
     #[wasm_bindgen(getter)]
     pub fn uri(&mut self) -> String {
-        if self.uri.len() != 0 {
+        if !self.uri.is_empty() {
             return self.uri.clone();
         }
         let binary = self.encode_to_binary();
@@ -139,7 +354,7 @@ impl DecodedSignature {
             DATA_URI_PREFIX,
             general_purpose::STANDARD.encode(binary.unwrap())
         ).clone();    
-        return self.uri.clone();
+        self.uri.clone()
     }
 
     #[wasm_bindgen(getter)]
@@ -149,13 +364,114 @@ impl DecodedSignature {
 
     #[wasm_bindgen(getter)]
     pub fn number_samples(&mut self) -> usize {
-        self.i16_buffer().len()
+        match self._number_samples {
+            Some(number_samples) => number_samples,
+            None => self.i16_buffer().len(),
+        }
     }
 
     fn i16_buffer(&mut self) -> &Vec<i16> {
-        if self._i16_buffer.len() == 0 {
-            self._i16_buffer = resample(self.orig_sample_rate_hz, self.orig_channel_count, &self.f32_buffer, 16000);
+        if self._i16_buffer.is_empty() {
+            self._i16_buffer = resample(self.orig_sample_rate_hz, self.orig_channel_count, &self.f32_buffer, self.sample_rate_hz as i32);
         }
         &self._i16_buffer
     }
+
+    pub(crate) fn frequency_band_to_sound_peaks(&mut self) -> &HashMap<FrequencyBand, Vec<FrequencyPeak>> {
+        self.generate_peaks(|_| true);
+        self._frequency_band_to_sound_peaks.as_ref().unwrap()
+    }
+
+    /// Generates the peaks unless `on_progress` returns false first, returning whether they are available.
+    pub(crate) fn generate_peaks(&mut self, on_progress: impl FnMut(f32) -> bool) -> bool {
+        if self._frequency_band_to_sound_peaks.is_none() {
+            let sample_rate_hz = self.sample_rate_hz;
+            let frequency_band_to_sound_peaks = SignatureGenerator::frequency_band_to_sound_peaks_at(self.i16_buffer(), sample_rate_hz, on_progress);
+            if frequency_band_to_sound_peaks.is_none() {
+                return false;
+            }
+            self._frequency_band_to_sound_peaks = frequency_band_to_sound_peaks;
+            self._pcm_stats = PcmStats::measure(&self._i16_buffer, self.sample_rate_hz);
+            // Only the peaks are needed from here on, so the PCM buffers are freed
+            self._number_samples = Some(self._i16_buffer.len());
+            self.f32_buffer = Vec::new();
+            self._i16_buffer = Vec::new();
+        }
+        true
+    }
+}
+
+impl DecodedSignature {
+    /// Judges whether the signature is silent with the given thresholds, remembering the verdict for `isSilent`.
+    pub(crate) fn mark_silence(&mut self, options: &SilenceOptions) -> bool {
+        let silent = self.detect_silence(options);
+        self._silent = Some(silent);
+        silent
+    }
+
+    fn detect_silence(&mut self, options: &SilenceOptions) -> bool {
+        let peaks_per_band = self.peak_count() as f32 / FrequencyBand::ALL.len() as f32;
+        self._pcm_stats.is_some_and(|pcm_stats| pcm_stats.rms_db < options.threshold_db) || peaks_per_band < options.min_peaks_per_band as f32
+    }
+}
+
+/// Signatures of a song file along with what was found while decoding it
+#[wasm_bindgen]
+pub struct Recognition {
+    signatures: Vec<DecodedSignature>,
+    report: DecodeReport,
+}
+
+impl Recognition {
+    pub fn new(signatures: Vec<DecodedSignature>, report: DecodeReport) -> Recognition {
+        Recognition { signatures, report }
+    }
+}
+
+#[wasm_bindgen]
+impl Recognition {
+    #[wasm_bindgen(getter)]
+    pub fn signatures(&self) -> Vec<DecodedSignature> {
+        self.signatures.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn report(&self) -> DecodeReport {
+        self.report.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprinting::test_audio::{noise, to_i16};
+
+    #[test]
+    fn encodes_and_decodes_signatures_at_every_sample_rate() {
+        for sample_rate_hz in SAMPLE_RATES {
+            let mut signature = DecodedSignature::from_pcm(to_i16(&noise(1, sample_rate_hz as usize * 3, 0.3)), sample_rate_hz);
+            let mut decoded = DecodedSignature::decode_from_binary(&signature.to_bytes()).unwrap();
+            assert_eq!(decoded.sample_rate_hz, sample_rate_hz);
+            assert_eq!(decoded.samplems(), 3000);
+            assert_eq!(decoded.peaks(), signature.peaks(), "{} Hz", sample_rate_hz);
+            assert!(decoded.peak_count() > 0, "{} Hz", sample_rate_hz);
+            assert!(decoded.frequency_band_to_sound_peaks().values().flatten().all(|peak| peak.sample_rate_hz == sample_rate_hz));
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_sample_rates() {
+        for sample_rate_hz in [0, 22050, 96000] {
+            assert!(check_sample_rate(sample_rate_hz).is_err(), "{} Hz", sample_rate_hz);
+        }
+
+        // A sample rate id past the end of the list
+        let mut bytes = DecodedSignature::from_pcm(to_i16(&noise(1, 16000, 0.3)), 16000).to_bytes();
+        bytes[28..32].copy_from_slice(&(7u32 << 27).to_le_bytes());
+        let mut hasher = Hasher::new();
+        hasher.update(&bytes[8..]);
+        bytes[4..8].copy_from_slice(&hasher.finalize().to_le_bytes());
+        let error = DecodedSignature::decode_from_binary(&bytes).err().unwrap();
+        assert_eq!(error.to_string(), "Invalid sample rate in signature header");
+    }
 }
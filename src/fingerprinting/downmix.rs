@@ -0,0 +1,214 @@
+use std::error::Error;
+
+use symphonia::core::audio::Channels;
+
+/// How interleaved multichannel samples are mixed into the mono signal that is fingerprinted
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Downmix {
+    /// Every channel weighted equally
+    #[default]
+    Average,
+    /// ITU-R BS.775 style: front left and right at full level, centre and surrounds at -3 dB,
+    /// LFE left out, so dialogue and rumble don't drown out the music of film audio
+    Itu,
+    /// A single channel by its position in the interleaved samples
+    Index(usize),
+    /// A single channel by its position in the layout, such as `Channels::FRONT_CENTRE`
+    Position(Channels),
+    /// Half the sum of front left and right, what both channels share
+    Mid,
+    /// Half the difference of front left and right, cancelling what is panned to the centre (often vocals)
+    Side,
+    /// The channel with the most energy, LFE left out
+    Loudest,
+}
+
+impl Downmix {
+    /// Parses `"average"`, `"itu"`, `"mid"`, `"side"`, `"loudest"` or a channel
+    /// position: `"left"`, `"right"`, `"centre"` (or `"center"`) and `"lfe"`.
+    pub fn parse(value: &str) -> Option<Downmix> {
+        Some(match value.to_ascii_lowercase().as_str() {
+            "average" => Downmix::Average,
+            "itu" => Downmix::Itu,
+            "mid" => Downmix::Mid,
+            "side" => Downmix::Side,
+            "loudest" => Downmix::Loudest,
+            "left" => Downmix::Position(Channels::FRONT_LEFT),
+            "right" => Downmix::Position(Channels::FRONT_RIGHT),
+            "centre" | "center" => Downmix::Position(Channels::FRONT_CENTRE),
+            "lfe" => Downmix::Position(Channels::LFE1),
+            _ => return None,
+        })
+    }
+
+    /// Mixes interleaved samples laid out as `channels` (symphonia interleaves them in
+    /// the order of their bits) into one channel. Layouts without the channel picked by
+    /// index or position are an error, mono samples are otherwise returned as they are.
+    pub fn apply(&self, samples: &[f32], channels: Channels) -> Result<Vec<f32>, Box<dyn Error>> {
+        let channel_count = channels.count();
+        let position = |channel: Channels| channels.iter().position(|c| c == channel);
+        match self {
+            // An unknown layout holds a single channel
+            Downmix::Index(index) if *index >= channel_count.max(1) => {
+                return Err(format!("No channel {} in audio with {} channels", index, channel_count.max(1)).into());
+            }
+            Downmix::Position(channel) if position(*channel).is_none() => {
+                return Err(format!("No {:?} channel in the {:?} channel layout", channel, channels).into());
+            }
+            _ => {}
+        }
+        if channel_count <= 1 {
+            return Ok(samples.to_vec());
+        }
+        let left = position(Channels::FRONT_LEFT).unwrap_or(0);
+        let right = position(Channels::FRONT_RIGHT).unwrap_or(1);
+
+        let weights: Vec<f32> = match self {
+            Downmix::Average => vec![1.0; channel_count],
+            Downmix::Itu => channels.iter().map(itu_weight).collect(),
+            Downmix::Index(index) => one_hot(channel_count, *index),
+            Downmix::Position(channel) => one_hot(channel_count, position(*channel).unwrap_or_default()),
+            Downmix::Mid => {
+                let mut weights = vec![0.0; channel_count];
+                weights[left] += 1.0;
+                weights[right] += 1.0;
+                weights
+            }
+            Downmix::Side => {
+                let mut weights = vec![0.0; channel_count];
+                weights[left] = 0.5;
+                weights[right] = -0.5;
+                return Ok(mix(samples, &weights));
+            }
+            Downmix::Loudest => one_hot(channel_count, loudest_channel(samples, channels)),
+        };
+
+        // Weights are normalized so correlated full scale input stays within full scale
+        let total: f32 = weights.iter().sum();
+        if total <= 0.0 {
+            return Ok(vec![0.0; samples.len() / channel_count]);
+        }
+        Ok(mix(samples, &weights.iter().map(|weight| weight / total).collect::<Vec<f32>>()))
+    }
+}
+
+fn itu_weight(channel: Channels) -> f32 {
+    if channel == Channels::FRONT_LEFT || channel == Channels::FRONT_RIGHT {
+        1.0
+    } else if channel == Channels::LFE1 || channel == Channels::LFE2 {
+        0.0
+    } else {
+        std::f32::consts::FRAC_1_SQRT_2
+    }
+}
+
+fn one_hot(channel_count: usize, index: usize) -> Vec<f32> {
+    let mut weights = vec![0.0; channel_count];
+    weights[index] = 1.0;
+    weights
+}
+
+fn mix(samples: &[f32], weights: &[f32]) -> Vec<f32> {
+    samples
+        .chunks_exact(weights.len())
+        .map(|frame| frame.iter().zip(weights).map(|(sample, weight)| sample * weight).sum())
+        .collect()
+}
+
+fn loudest_channel(samples: &[f32], channels: Channels) -> usize {
+    let channel_count = channels.count();
+    let mut energies = vec![0f64; channel_count];
+    for frame in samples.chunks_exact(channel_count) {
+        for (energy, sample) in energies.iter_mut().zip(frame) {
+            *energy += (*sample as f64) * (*sample as f64);
+        }
+    }
+    channels
+        .iter()
+        .zip(energies)
+        .enumerate()
+        .filter(|(_, (channel, _))| *channel != Channels::LFE1 && *channel != Channels::LFE2)
+        .max_by(|(_, (_, a)), (_, (_, b))| a.total_cmp(b))
+        .map_or(0, |(index, _)| index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEREO: Channels = Channels::FRONT_LEFT.union(Channels::FRONT_RIGHT);
+
+    /// 5.1 laid out as front left, right, centre, LFE, rear left and right
+    fn surround() -> Channels {
+        STEREO | Channels::FRONT_CENTRE | Channels::LFE1 | Channels::REAR_LEFT | Channels::REAR_RIGHT
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert!((actual - expected).abs() < 1e-6, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn mixes_stereo_by_each_mode() {
+        // Frames of (left, right)
+        let samples = [1.0, 0.0, 0.5, -0.5, 0.2, 0.6];
+        let mix = |downmix: Downmix| downmix.apply(&samples, STEREO).unwrap();
+        assert_close(&mix(Downmix::Average), &[0.5, 0.0, 0.4]);
+        assert_close(&mix(Downmix::Itu), &[0.5, 0.0, 0.4]);
+        assert_close(&mix(Downmix::Mid), &[0.5, 0.0, 0.4]);
+        assert_close(&mix(Downmix::Side), &[0.5, 0.5, -0.2]);
+        assert_close(&mix(Downmix::Index(1)), &[0.0, -0.5, 0.6]);
+        assert_close(&mix(Downmix::Position(Channels::FRONT_LEFT)), &[1.0, 0.5, 0.2]);
+        assert_close(&mix(Downmix::Loudest), &[1.0, 0.5, 0.2]);
+    }
+
+    #[test]
+    fn normalizes_itu_weights_and_leaves_lfe_out() {
+        // The same full scale sample on every channel stays at full scale
+        let correlated = Downmix::Itu.apply(&[1.0; 6], surround()).unwrap();
+        assert_close(&correlated, &[1.0]);
+
+        // Only the LFE, which is left out
+        assert_close(&Downmix::Itu.apply(&[0.0, 0.0, 0.0, 1.0, 0.0, 0.0], surround()).unwrap(), &[0.0]);
+
+        // Centre at -3 dB relative to the front channels
+        let total = 2.0 + 3.0 * std::f32::consts::FRAC_1_SQRT_2;
+        let left = Downmix::Itu.apply(&[1.0, 0.0, 0.0, 0.0, 0.0, 0.0], surround()).unwrap();
+        let centre = Downmix::Itu.apply(&[0.0, 0.0, 1.0, 0.0, 0.0, 0.0], surround()).unwrap();
+        assert_close(&left, &[1.0 / total]);
+        assert_close(&centre, &[std::f32::consts::FRAC_1_SQRT_2 / total]);
+    }
+
+    #[test]
+    fn picks_the_loudest_channel_but_the_lfe() {
+        let frames: Vec<f32> = (0..4).flat_map(|_| [0.1, 0.2, 0.5, 0.9, 0.3, 0.0]).collect();
+        assert_close(&Downmix::Loudest.apply(&frames, surround()).unwrap(), &[0.5; 4]);
+    }
+
+    #[test]
+    fn rejects_missing_channels() {
+        assert!(Downmix::Index(2).apply(&[0.0; 4], STEREO).is_err());
+        assert!(Downmix::Position(Channels::FRONT_CENTRE).apply(&[0.0; 4], STEREO).is_err());
+        // Mono too, rather than returning its samples as if the channel were there
+        assert!(Downmix::Index(1).apply(&[0.0; 2], Channels::FRONT_LEFT).is_err());
+        assert!(Downmix::Position(Channels::LFE1).apply(&[0.0; 2], Channels::FRONT_LEFT).is_err());
+    }
+
+    #[test]
+    fn returns_mono_samples_as_they_are() {
+        let samples = [0.25, -0.5, 1.0];
+        for downmix in [Downmix::Average, Downmix::Itu, Downmix::Side, Downmix::Loudest, Downmix::Index(0), Downmix::Position(Channels::FRONT_LEFT)] {
+            assert_eq!(downmix.apply(&samples, Channels::FRONT_LEFT).unwrap(), samples);
+        }
+    }
+
+    #[test]
+    fn parses_modes_and_positions() {
+        assert_eq!(Downmix::parse("ITU"), Some(Downmix::Itu));
+        assert_eq!(Downmix::parse("center"), Some(Downmix::Position(Channels::FRONT_CENTRE)));
+        assert_eq!(Downmix::parse("lfe"), Some(Downmix::Position(Channels::LFE1)));
+        assert_eq!(Downmix::parse("surround"), None);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,254 @@
+/// Level the highest peak is scaled to by `Normalization::Peak`, -1 dBFS
+const PEAK_TARGET: f32 = 0.891;
+/// Level `Normalization::Rms` and `Normalization::Agc` scale to, -20 dBFS
+const RMS_TARGET: f32 = 0.1;
+/// Quiet recordings are amplified by at most 40 dB, so silence and hiss aren't blown up
+const MAX_GAIN: f32 = 100.0;
+/// Blocks the AGC measures the level of are 100 ms long
+const AGC_BLOCKS_PER_SECOND: usize = 10;
+/// Blocks quieter than this are treated as silence, the AGC holds its gain over them
+const AGC_SILENCE: f32 = 0.000_3;
+
+/// How the level of the mono signal is adjusted before it is quantized to 16 bits
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Normalization {
+    /// Samples are left as decoded
+    #[default]
+    None,
+    /// Scales the signal so its highest peak reaches -1 dBFS
+    Peak,
+    /// Scales the signal so its RMS level reaches -20 dBFS, peaks above full scale clip
+    Rms,
+    /// Automatic gain control: follows the level of 100 ms blocks toward -20 dBFS,
+    /// lowering the gain quickly and raising it slowly
+    Agc,
+}
+
+impl Normalization {
+    /// Parses `"none"`, `"peak"`, `"rms"` or `"agc"`.
+    pub fn parse(value: &str) -> Option<Normalization> {
+        match value.to_ascii_lowercase().as_str() {
+            "none" => Some(Normalization::None),
+            "peak" => Some(Normalization::Peak),
+            "rms" => Some(Normalization::Rms),
+            "agc" => Some(Normalization::Agc),
+            _ => None,
+        }
+    }
+
+    /// Adjusts the level of the samples in place, returning the gain applied in dB
+    /// (for the AGC, the average over the signal).
+    pub fn apply(&self, samples: &mut [f32], sample_rate: u32) -> f32 {
+        let gain = match self {
+            Normalization::None => return 0.0,
+            Normalization::Peak => {
+                let peak = samples.iter().fold(0f32, |peak, sample| peak.max(sample.abs()));
+                if peak <= 0.0 {
+                    return 0.0;
+                }
+                (PEAK_TARGET / peak).min(MAX_GAIN)
+            }
+            Normalization::Rms => {
+                let rms = rms(samples);
+                if rms <= 0.0 {
+                    return 0.0;
+                }
+                (RMS_TARGET / rms).min(MAX_GAIN)
+            }
+            Normalization::Agc => return agc(samples, (sample_rate as usize / AGC_BLOCKS_PER_SECOND).max(1)),
+        };
+        for sample in samples.iter_mut() {
+            *sample *= gain;
+        }
+        to_db(gain)
+    }
+}
+
+fn rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    (samples.iter().map(|sample| (*sample as f64) * (*sample as f64)).sum::<f64>() / samples.len() as f64).sqrt() as f32
+}
+
+fn to_db(gain: f32) -> f32 {
+    20.0 * gain.log10()
+}
+
+/// Level in dBFS of the mean square of 16 bit samples, floored at -120 dBFS for silence
+pub fn mean_square_db(mean_square: f64) -> f32 {
+    (10.0 * (mean_square / (i16::MAX as f64 * i16::MAX as f64)).max(1e-12).log10()) as f32
+}
+
+/// RMS level of 16 bit samples in dBFS
+pub fn rms_db(samples: &[i16]) -> f32 {
+    mean_square_db(samples.iter().map(|sample| (*sample as f64) * (*sample as f64)).sum::<f64>() / samples.len().max(1) as f64)
+}
+
+fn agc(samples: &mut [f32], block_length: usize) -> f32 {
+    // Gain per block, smoothed in dB: falls within a block, rises by at most 1 dB per block
+    let mut block_gains_db = Vec::with_capacity(samples.len() / block_length + 1);
+    let mut gain_db: Option<f32> = None;
+    for block in samples.chunks(block_length) {
+        let rms = rms(block);
+        let wanted_db = to_db((RMS_TARGET / rms.max(f32::MIN_POSITIVE)).min(MAX_GAIN));
+        gain_db = Some(match gain_db {
+            None => wanted_db,
+            Some(gain_db) if rms < AGC_SILENCE => gain_db,
+            Some(gain_db) if wanted_db < gain_db => wanted_db,
+            Some(gain_db) => wanted_db.min(gain_db + 1.0),
+        });
+        block_gains_db.push(gain_db.unwrap_or_default());
+    }
+
+    // Interpolate between the gains of neighbouring blocks so the level doesn't step
+    let mut previous_db = block_gains_db.first().copied().unwrap_or_default();
+    for (block, &block_gain_db) in samples.chunks_mut(block_length).zip(&block_gains_db) {
+        let length = block.len() as f32;
+        for (i, sample) in block.iter_mut().enumerate() {
+            let gain_db = previous_db + (block_gain_db - previous_db) * (i as f32 + 1.0) / length;
+            *sample *= 10f32.powf(gain_db / 20.0);
+        }
+        previous_db = block_gain_db;
+    }
+
+    if block_gains_db.is_empty() {
+        return 0.0;
+    }
+    block_gains_db.iter().sum::<f32>() / block_gains_db.len() as f32
+}
+
+/// Converts samples to 16 bits, optionally adding TPDF dither, returning them with
+/// how many had to be clipped to full scale.
+pub fn quantize(samples: &[f32], dither: bool) -> (Vec<i16>, u32) {
+    // Fixed seed, so the same audio always gives the same signature
+    let mut random = XorShift(0x9E37_79B9);
+    let mut clipped_samples = 0;
+    let pcm = samples
+        .iter()
+        .map(|&sample| {
+            let mut value = sample * i16::MAX as f32;
+            if dither {
+                // Triangular noise of up to one least significant bit either way
+                value = (value + random.next_unit() - random.next_unit()).round();
+            }
+            if !(i16::MIN as f32..=i16::MAX as f32).contains(&value) {
+                clipped_samples += 1;
+            }
+            value as i16
+        })
+        .collect();
+    (pcm, clipped_samples)
+}
+
+struct XorShift(u32);
+
+impl XorShift {
+    /// A number between 0 and 1
+    fn next_unit(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 as f32 / u32::MAX as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprinting::test_audio::{sine, to_i16};
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0f32, |peak, sample| peak.max(sample.abs()))
+    }
+
+    fn assert_near(actual: f32, expected: f32, tolerance: f32) {
+        assert!((actual - expected).abs() < tolerance, "{} is not {}", actual, expected);
+    }
+
+    #[test]
+    fn measures_the_rms_level_of_a_sine() {
+        // A full scale sine is 3 dB below full scale, halving its amplitude takes 6 dB off
+        assert_near(rms_db(&to_i16(&sine(1000.0, 16000, 16000, 1.0))), -3.01, 0.01);
+        assert_near(rms_db(&to_i16(&sine(1000.0, 16000, 16000, 0.5))), -9.03, 0.01);
+        assert_eq!(rms_db(&[0; 100]), -120.0);
+        assert_eq!(rms_db(&[]), -120.0);
+    }
+
+    #[test]
+    fn normalizes_peaks_to_minus_one_dbfs() {
+        let mut samples = sine(1000.0, 16000, 16000, 0.25);
+        let gain_db = Normalization::Peak.apply(&mut samples, 16000);
+        assert_near(peak(&samples), PEAK_TARGET, 1e-4);
+        assert_near(to_db(peak(&samples)), -1.0, 0.01);
+        assert_near(gain_db, to_db(PEAK_TARGET / 0.25), 1e-3);
+    }
+
+    #[test]
+    fn normalizes_the_rms_level_to_minus_twenty_dbfs() {
+        let mut samples = sine(1000.0, 16000, 16000, 0.01);
+        let gain_db = Normalization::Rms.apply(&mut samples, 16000);
+        assert_near(rms(&samples), RMS_TARGET, 1e-4);
+        assert_near(gain_db, 23.01, 0.01);
+    }
+
+    #[test]
+    fn limits_the_gain_and_leaves_silence_alone() {
+        let mut quiet = sine(1000.0, 16000, 16000, 1e-4);
+        assert_near(Normalization::Peak.apply(&mut quiet, 16000), 40.0, 1e-3);
+        assert_near(peak(&quiet), 0.01, 1e-4);
+
+        for normalization in [Normalization::None, Normalization::Peak, Normalization::Rms] {
+            let mut silence = vec![0f32; 1000];
+            assert_eq!(normalization.apply(&mut silence, 16000), 0.0);
+            assert!(silence.iter().all(|sample| *sample == 0.0));
+        }
+    }
+
+    #[test]
+    fn follows_the_level_lowering_the_gain_faster_than_raising_it() {
+        // Two seconds at each level, quiet then loud then quiet again
+        let mut samples = sine(1000.0, 16000, 32000, 0.01);
+        samples.extend(sine(1000.0, 16000, 32000, 0.5));
+        samples.extend(sine(1000.0, 16000, 32000, 0.01));
+        Normalization::Agc.apply(&mut samples, 16000);
+
+        // Settled at the end of each level
+        assert_near(rms(&samples[24000..32000]), RMS_TARGET, 0.01);
+        assert_near(rms(&samples[56000..64000]), RMS_TARGET, 0.01);
+        // Loud audio is brought down within a block, quiet audio after it only comes up 1 dB a block
+        assert!(rms(&samples[33600..35200]) < 0.15);
+        assert!(rms(&samples[65600..67200]) < 0.02);
+    }
+
+    #[test]
+    fn counts_the_samples_clipped_when_quantizing() {
+        let (pcm, clipped_samples) = quantize(&[0.5, -0.5, 1.5, -1.5, 1.0], false);
+        assert_eq!(pcm, [16383, -16383, i16::MAX, i16::MIN, i16::MAX]);
+        assert_eq!(clipped_samples, 2);
+    }
+
+    #[test]
+    fn dithers_with_triangular_noise_of_one_lsb() {
+        // A level of a third of a bit is lost without dither, and kept on average with it
+        let third_of_a_bit = vec![1.0 / 3.0 / i16::MAX as f32; 100_000];
+        assert!(quantize(&third_of_a_bit, false).0.iter().all(|sample| *sample == 0));
+
+        let (pcm, clipped_samples) = quantize(&third_of_a_bit, true);
+        assert_eq!(clipped_samples, 0);
+        assert!(pcm.iter().all(|sample| (-1..=1).contains(sample)));
+        let mean = pcm.iter().map(|sample| *sample as f64).sum::<f64>() / pcm.len() as f64;
+        assert!((mean - 1.0 / 3.0).abs() < 0.01, "{}", mean);
+
+        // The same for every call, so signatures are reproducible
+        assert_eq!(quantize(&third_of_a_bit, true).0, pcm);
+    }
+
+    #[test]
+    fn parses_modes() {
+        assert_eq!(Normalization::parse("AGC"), Some(Normalization::Agc));
+        assert_eq!(Normalization::parse("rms"), Some(Normalization::Rms));
+        assert_eq!(Normalization::parse("loudness"), None);
+    }
+}
+
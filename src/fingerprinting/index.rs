@@ -0,0 +1,659 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc32fast::Hasher;
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io::{Cursor, Read, Write};
+use std::ops::Deref;
+
+use crate::fingerprinting::decoded_signature::DEFAULT_SAMPLE_RATE;
+use crate::fingerprinting::landmarks::landmarks_from_peaks;
+use crate::fingerprinting::signature_generator::{peaks_sample_rate, FrequencyBand, FrequencyPeak};
+
+// An index file is a header followed by segments, each checksummed on its own
+// so that changes can be appended without rewriting what is already on disk:
+//
+//   header:   magic u32, version u32, next track id u32, crc32 of the previous 12 bytes u32
+//
+// The next track id is written when the whole index is, so ids of tracks dropped by
+// compaction are never given out again; ids in later segments can go beyond it.
+//   segment:  tag u32, crc32 of the body u32, body size u32, body
+//
+// A tracks segment body holds the tracks it adds, then their landmarks sorted
+// by hash so they can be binary searched in place:
+//
+//   track count u32, tracks (id u32, duration ms u32, name size u32, name padded to 4 bytes)
+//   landmark count u32, landmarks (hash u32, track id u32, fft pass number u32)
+//
+// A deletions segment body holds the ids of the tracks it removes:
+//
+//   track count u32, track ids u32
+
+const INDEX_MAGIC: u32 = 0xcafe1dec;
+const INDEX_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 16;
+
+const TRACKS_SEGMENT: u32 = 0x40000001;
+const DELETIONS_SEGMENT: u32 = 0x40000002;
+
+const LANDMARK_SIZE: usize = 12;
+
+/// Rate of the audio every track and query is fingerprinted at. Landmark hashes hold FFT bins
+/// and pass numbers, which only line up between peaks found at the same rate.
+pub const INDEX_SAMPLE_RATE: u32 = DEFAULT_SAMPLE_RATE;
+
+/// Minimum number of landmarks agreeing on an offset for a track to be reported
+const MIN_MATCHED_LANDMARKS: u32 = 5;
+
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Debug, Clone)]
+pub struct IndexTrack {
+    pub id: u32,
+    pub name: String,
+    #[wasm_bindgen(js_name = "durationMs")]
+    pub duration_ms: u32,
+}
+
+#[wasm_bindgen]
+#[derive(Debug, Clone)]
+pub struct IndexMatch {
+    #[wasm_bindgen(js_name = "trackId")]
+    pub track_id: u32,
+    /// Position in the track the query starts at
+    #[wasm_bindgen(js_name = "offsetMs")]
+    pub offset_ms: i32,
+    /// Fraction of the query landmarks found at the offset, between 0 and 1
+    pub score: f32,
+    #[wasm_bindgen(js_name = "matchedLandmarks")]
+    pub matched_landmarks: u32,
+}
+
+enum IndexData {
+    Owned(Vec<u8>),
+    #[cfg(not(target_arch = "wasm32"))]
+    Mapped(memmap2::Mmap),
+}
+
+impl Deref for IndexData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            IndexData::Owned(bytes) => bytes,
+            #[cfg(not(target_arch = "wasm32"))]
+            IndexData::Mapped(mmap) => mmap,
+        }
+    }
+}
+
+struct TracksSegment {
+    tracks: Vec<IndexTrack>,
+    landmarks_start: usize,
+    landmark_count: usize,
+}
+
+/// A catalogue of tracks fingerprinted as landmarks, stored in a versioned,
+/// checksummed binary format that can be appended to incrementally.
+pub struct FingerprintIndex {
+    data: IndexData,
+    segments: Vec<TracksSegment>,
+    deleted: HashSet<u32>,
+    pending_tracks: Vec<IndexTrack>,
+    /// (hash, track id, fft pass number) of the pending tracks, sorted by hash
+    pending_landmarks: Vec<(u32, u32, u32)>,
+    pending_deletions: Vec<u32>,
+    next_track_id: u32,
+    /// Whether `compact` rewrote the index, so `save` has to replace the file instead of appending to it
+    compacted: bool,
+}
+
+fn encode_header(next_track_id: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    header.write_u32::<LittleEndian>(INDEX_MAGIC).unwrap();
+    header.write_u32::<LittleEndian>(INDEX_VERSION).unwrap();
+    header.write_u32::<LittleEndian>(next_track_id).unwrap();
+    let mut hasher = Hasher::new();
+    hasher.update(&header);
+    header.write_u32::<LittleEndian>(hasher.finalize()).unwrap();
+    header
+}
+
+fn encode_segment(tag: u32, body: Vec<u8>) -> Vec<u8> {
+    let mut segment = Vec::with_capacity(12 + body.len());
+    let mut hasher = Hasher::new();
+    hasher.update(&body);
+    segment.write_u32::<LittleEndian>(tag).unwrap();
+    segment.write_u32::<LittleEndian>(hasher.finalize()).unwrap();
+    segment.write_u32::<LittleEndian>(body.len() as u32).unwrap();
+    segment.extend_from_slice(&body);
+    segment
+}
+
+fn encode_tracks_segment(tracks: &[IndexTrack], landmarks: &[(u32, u32, u32)]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(8 + landmarks.len() * LANDMARK_SIZE);
+    body.write_u32::<LittleEndian>(tracks.len() as u32).unwrap();
+    for track in tracks {
+        body.write_u32::<LittleEndian>(track.id).unwrap();
+        body.write_u32::<LittleEndian>(track.duration_ms).unwrap();
+        body.write_u32::<LittleEndian>(track.name.len() as u32).unwrap();
+        body.extend_from_slice(track.name.as_bytes());
+        body.resize(body.len() + (4 - track.name.len() % 4) % 4, 0);
+    }
+    body.write_u32::<LittleEndian>(landmarks.len() as u32).unwrap();
+    for (hash, track_id, fft_pass_number) in landmarks {
+        body.write_u32::<LittleEndian>(*hash).unwrap();
+        body.write_u32::<LittleEndian>(*track_id).unwrap();
+        body.write_u32::<LittleEndian>(*fft_pass_number).unwrap();
+    }
+    encode_segment(TRACKS_SEGMENT, body)
+}
+
+fn encode_deletions_segment(track_ids: &[u32]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(4 + track_ids.len() * 4);
+    body.write_u32::<LittleEndian>(track_ids.len() as u32).unwrap();
+    for track_id in track_ids {
+        body.write_u32::<LittleEndian>(*track_id).unwrap();
+    }
+    encode_segment(DELETIONS_SEGMENT, body)
+}
+
+fn check_sample_rate(frequency_band_to_sound_peaks: &HashMap<FrequencyBand, Vec<FrequencyPeak>>) -> Result<(), Box<dyn Error>> {
+    match peaks_sample_rate(frequency_band_to_sound_peaks) {
+        Some(sample_rate_hz) if sample_rate_hz != INDEX_SAMPLE_RATE => Err(format!(
+            "Indexes hold signatures of {} Hz audio, got one of {} Hz",
+            INDEX_SAMPLE_RATE, sample_rate_hz
+        )
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+fn landmark_at(data: &[u8], landmarks_start: usize, index: usize) -> (u32, u32, u32) {
+    let mut cursor = Cursor::new(&data[landmarks_start + index * LANDMARK_SIZE..]);
+    (
+        cursor.read_u32::<LittleEndian>().unwrap(),
+        cursor.read_u32::<LittleEndian>().unwrap(),
+        cursor.read_u32::<LittleEndian>().unwrap(),
+    )
+}
+
+fn parse(data: &[u8]) -> Result<(Vec<TracksSegment>, HashSet<u32>), Box<dyn Error>> {
+    let mut cursor = Cursor::new(data);
+    if data.len() < HEADER_SIZE || cursor.read_u32::<LittleEndian>()? != INDEX_MAGIC {
+        return Err("Invalid index header".into());
+    }
+    let version = cursor.read_u32::<LittleEndian>()?;
+    if version != INDEX_VERSION {
+        return Err(format!("Unsupported index version {}", version).into());
+    }
+    let mut hasher = Hasher::new();
+    hasher.update(&data[..12]);
+    cursor.set_position(12);
+    if cursor.read_u32::<LittleEndian>()? != hasher.finalize() {
+        return Err("Invalid index header checksum".into());
+    }
+
+    let mut segments = Vec::new();
+    let mut deleted = HashSet::new();
+    while (cursor.position() as usize) < data.len() {
+        let tag = cursor.read_u32::<LittleEndian>()?;
+        let crc32 = cursor.read_u32::<LittleEndian>()?;
+        let size = cursor.read_u32::<LittleEndian>()? as usize;
+        let body_start = cursor.position() as usize;
+        let body_end = body_start.checked_add(size).ok_or("Truncated index segment")?;
+        let body = data.get(body_start..body_end).ok_or("Truncated index segment")?;
+        let mut hasher = Hasher::new();
+        hasher.update(body);
+        if hasher.finalize() != crc32 {
+            return Err("Invalid index segment checksum".into());
+        }
+
+        let mut body_cursor = Cursor::new(body);
+        match tag {
+            TRACKS_SEGMENT => {
+                // Counts and sizes come from the file, so they are checked against what is left
+                // of the segment before anything is allocated from them
+                let track_count = body_cursor.read_u32::<LittleEndian>()?;
+                let mut tracks = Vec::with_capacity((track_count as usize).min(size / 12));
+                for _ in 0..track_count {
+                    let id = body_cursor.read_u32::<LittleEndian>()?;
+                    let duration_ms = body_cursor.read_u32::<LittleEndian>()?;
+                    let name_size = body_cursor.read_u32::<LittleEndian>()? as usize;
+                    let padded_size = name_size.checked_add((4 - name_size % 4) % 4).ok_or("Invalid index tracks segment")?;
+                    if padded_size > size - body_cursor.position() as usize {
+                        return Err("Invalid index tracks segment".into());
+                    }
+                    let mut name = vec![0u8; padded_size];
+                    body_cursor.read_exact(&mut name)?;
+                    name.truncate(name_size);
+                    tracks.push(IndexTrack { id, name: String::from_utf8(name)?, duration_ms });
+                }
+                let landmark_count = body_cursor.read_u32::<LittleEndian>()? as usize;
+                let landmarks_start = body_start + body_cursor.position() as usize;
+                let landmarks_end = landmark_count
+                    .checked_mul(LANDMARK_SIZE)
+                    .and_then(|landmarks_size| landmarks_size.checked_add(body_cursor.position() as usize));
+                if landmarks_end != Some(size) {
+                    return Err("Invalid index tracks segment".into());
+                }
+                segments.push(TracksSegment { tracks, landmarks_start, landmark_count });
+            }
+            DELETIONS_SEGMENT => {
+                let track_count = body_cursor.read_u32::<LittleEndian>()?;
+                for _ in 0..track_count {
+                    deleted.insert(body_cursor.read_u32::<LittleEndian>()?);
+                }
+            }
+            _ => return Err(format!("Unknown index segment {:#x}", tag).into()),
+        }
+        cursor.set_position(body_end as u64);
+    }
+    Ok((segments, deleted))
+}
+
+impl Default for FingerprintIndex {
+    fn default() -> Self {
+        FingerprintIndex::new()
+    }
+}
+
+impl FingerprintIndex {
+    pub fn new() -> FingerprintIndex {
+        FingerprintIndex::from_data(IndexData::Owned(encode_header(0))).unwrap()
+    }
+
+    /// Loads an index from the bytes of an index file.
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<FingerprintIndex, Box<dyn Error>> {
+        FingerprintIndex::from_data(IndexData::Owned(bytes))
+    }
+
+    /// Memory-maps an existing index file.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn open(path: &std::path::Path) -> Result<FingerprintIndex, Box<dyn Error>> {
+        let file = std::fs::File::open(path)?;
+        // SAFETY: the index only ever appends to or atomically replaces its file,
+        // so the mapped bytes are not modified while mapped by this process.
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+        FingerprintIndex::from_data(IndexData::Mapped(mmap))
+    }
+
+    fn from_data(data: IndexData) -> Result<FingerprintIndex, Box<dyn Error>> {
+        let (segments, deleted) = parse(&data)?;
+        let header_next_track_id = u32::from_le_bytes(data[8..12].try_into().unwrap());
+        let next_track_id = segments
+            .iter()
+            .flat_map(|segment| segment.tracks.iter().map(|track| track.id))
+            .chain(deleted.iter().copied())
+            .map(|track_id| track_id.checked_add(1).ok_or("Invalid track id in index"))
+            .collect::<Result<Vec<u32>, _>>()?
+            .into_iter()
+            .max()
+            .unwrap_or(0)
+            .max(header_next_track_id);
+        Ok(FingerprintIndex {
+            data,
+            segments,
+            deleted,
+            pending_tracks: Vec::new(),
+            pending_landmarks: Vec::new(),
+            pending_deletions: Vec::new(),
+            next_track_id,
+            compacted: false,
+        })
+    }
+
+    /// Tracks currently in the index, including unsaved ones.
+    pub fn tracks(&self) -> Vec<&IndexTrack> {
+        self.segments
+            .iter()
+            .flat_map(|segment| segment.tracks.iter())
+            .chain(self.pending_tracks.iter())
+            .filter(|track| !self.deleted.contains(&track.id))
+            .collect()
+    }
+
+    /// Whether tracks were added or removed, or the index compacted, since it was loaded or saved.
+    pub fn has_unsaved_changes(&self) -> bool {
+        !self.pending_tracks.is_empty() || !self.pending_deletions.is_empty() || self.compacted
+    }
+
+    /// Adds a track fingerprinted from its peaks, found at `INDEX_SAMPLE_RATE`, and returns its id.
+    pub fn add_track(
+        &mut self,
+        name: &str,
+        frequency_band_to_sound_peaks: &HashMap<FrequencyBand, Vec<FrequencyPeak>>,
+        duration_ms: u32,
+    ) -> Result<u32, Box<dyn Error>> {
+        check_sample_rate(frequency_band_to_sound_peaks)?;
+        let track_id = self.next_track_id;
+        self.next_track_id += 1;
+
+        self.pending_tracks.push(IndexTrack { id: track_id, name: name.to_string(), duration_ms });
+        self.pending_landmarks.extend(
+            landmarks_from_peaks(frequency_band_to_sound_peaks)
+                .into_iter()
+                .map(|landmark| (landmark.hash, track_id, landmark.fft_pass_number)),
+        );
+        self.pending_landmarks.sort_unstable();
+        Ok(track_id)
+    }
+
+    /// Removes a track, returning whether it was in the index.
+    pub fn remove_track(&mut self, track_id: u32) -> bool {
+        if let Some(position) = self.pending_tracks.iter().position(|track| track.id == track_id) {
+            self.pending_tracks.remove(position);
+            self.pending_landmarks.retain(|landmark| landmark.1 != track_id);
+            return true;
+        }
+        let exists = self
+            .segments
+            .iter()
+            .any(|segment| segment.tracks.iter().any(|track| track.id == track_id));
+        if !exists || !self.deleted.insert(track_id) {
+            return false;
+        }
+        self.pending_deletions.push(track_id);
+        true
+    }
+
+    /// Segments holding the unsaved changes, to be appended to the index file.
+    fn encode_pending(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        if !self.pending_tracks.is_empty() {
+            bytes.extend(encode_tracks_segment(&self.pending_tracks, &self.pending_landmarks));
+        }
+        if !self.pending_deletions.is_empty() {
+            bytes.extend(encode_deletions_segment(&self.pending_deletions));
+        }
+        bytes
+    }
+
+    /// Serializes the whole index, including unsaved changes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.data.to_vec();
+        bytes.extend(self.encode_pending());
+        bytes
+    }
+
+    /// Appends the unsaved changes to the index file at `path`, which must be
+    /// the file this index was opened from or a new file. A compacted index
+    /// replaces the file as `write` does.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn save(&mut self, path: &std::path::Path) -> Result<(), Box<dyn Error>> {
+        if self.compacted {
+            return self.write(path);
+        }
+        let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+        let file_size = file.metadata()?.len() as usize;
+        if file_size == 0 {
+            file.write_all(&self.to_bytes())?;
+        } else if file_size == self.data.len() {
+            file.write_all(&self.encode_pending())?;
+        } else {
+            return Err("Index file was modified by another writer".into());
+        }
+        file.sync_all()?;
+        *self = FingerprintIndex::open(path)?;
+        Ok(())
+    }
+
+    /// Replaces the index file at `path` with the whole index, writing to a
+    /// temporary file first so readers never see a partial index.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn write(&mut self, path: &std::path::Path) -> Result<(), Box<dyn Error>> {
+        let temporary_path = path.with_extension("tmp");
+        let mut file = std::fs::File::create(&temporary_path)?;
+        file.write_all(&self.to_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&temporary_path, path)?;
+        *self = FingerprintIndex::open(path)?;
+        Ok(())
+    }
+
+    /// Rewrites the index as a single segment without removed tracks. Ids of
+    /// removed tracks are never reused, the header keeping the next one. The
+    /// next `save` replaces the index file rather than appending to it.
+    pub fn compact(&mut self) {
+        let tracks: Vec<IndexTrack> = self.tracks().into_iter().cloned().collect();
+        let mut landmarks: Vec<(u32, u32, u32)> = self
+            .segments
+            .iter()
+            .flat_map(|segment| {
+                (0..segment.landmark_count).map(|index| landmark_at(&self.data, segment.landmarks_start, index))
+            })
+            .filter(|landmark| !self.deleted.contains(&landmark.1))
+            .chain(self.pending_landmarks.iter().copied())
+            .collect();
+        landmarks.sort_unstable();
+
+        let mut bytes = encode_header(self.next_track_id);
+        if !tracks.is_empty() {
+            bytes.extend(encode_tracks_segment(&tracks, &landmarks));
+        }
+        *self = FingerprintIndex::from_bytes(bytes).unwrap();
+        self.compacted = true;
+    }
+
+    /// Calls `f` with the track id and fft pass number of every landmark stored under `hash`
+    fn for_each_landmark(&self, hash: u32, mut f: impl FnMut(u32, u32)) {
+        for segment in &self.segments {
+            // Lower bound binary search over the landmarks sorted by hash
+            let (mut low, mut high) = (0, segment.landmark_count);
+            while low < high {
+                let middle = (low + high) / 2;
+                if landmark_at(&self.data, segment.landmarks_start, middle).0 < hash {
+                    low = middle + 1;
+                } else {
+                    high = middle;
+                }
+            }
+            for index in low..segment.landmark_count {
+                let landmark = landmark_at(&self.data, segment.landmarks_start, index);
+                if landmark.0 != hash {
+                    break;
+                }
+                f(landmark.1, landmark.2);
+            }
+        }
+        let start = self.pending_landmarks.partition_point(|landmark| landmark.0 < hash);
+        for landmark in self.pending_landmarks[start..].iter().take_while(|landmark| landmark.0 == hash) {
+            f(landmark.1, landmark.2);
+        }
+    }
+
+    /// Finds the tracks matching a query's peaks, found at `INDEX_SAMPLE_RATE`, best match first.
+    pub fn query(&self, frequency_band_to_sound_peaks: &HashMap<FrequencyBand, Vec<FrequencyPeak>>) -> Result<Vec<IndexMatch>, Box<dyn Error>> {
+        check_sample_rate(frequency_band_to_sound_peaks)?;
+        let landmarks = landmarks_from_peaks(frequency_band_to_sound_peaks);
+
+        // Vote for the offset into each track implied by every landmark found
+        let mut votes: HashMap<(u32, i64), u32> = HashMap::new();
+        for landmark in &landmarks {
+            for hash in landmark.tolerant_hashes() {
+                self.for_each_landmark(hash, |track_id, fft_pass_number| {
+                    if !self.deleted.contains(&track_id) {
+                        let offset = fft_pass_number as i64 - landmark.fft_pass_number as i64;
+                        *votes.entry((track_id, offset)).or_default() += 1;
+                    }
+                });
+            }
+        }
+
+        // Keep the best offset of each track, counting votes one FFT pass either side
+        let mut best: HashMap<u32, (i64, u32)> = HashMap::new();
+        for (track_id, offset) in votes.keys() {
+            let count: u32 = (offset - 1..=offset + 1)
+                .filter_map(|o| votes.get(&(*track_id, o)))
+                .sum();
+            let entry = best.entry(*track_id).or_insert((*offset, 0));
+            if count > entry.1 || (count == entry.1 && *offset < entry.0) {
+                *entry = (*offset, count);
+            }
+        }
+
+        let mut matches: Vec<IndexMatch> = best
+            .into_iter()
+            .filter(|(_, (_, count))| *count >= MIN_MATCHED_LANDMARKS)
+            .map(|(track_id, (offset, count))| IndexMatch {
+                track_id,
+                offset_ms: (offset * 128 * 1000 / INDEX_SAMPLE_RATE as i64) as i32,
+                score: (count as f32 / landmarks.len() as f32).min(1.0),
+                matched_landmarks: count,
+            })
+            .collect();
+        matches.sort_by(|x, y| y.matched_landmarks.cmp(&x.matched_landmarks).then(x.track_id.cmp(&y.track_id)));
+        Ok(matches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Peaks spread over about 16 seconds, different for every seed
+    fn peaks(seed: u32) -> HashMap<FrequencyBand, Vec<FrequencyPeak>> {
+        let mut state = seed.wrapping_mul(2_654_435_761).max(1);
+        let frequency_peaks = (0..2000)
+            .step_by(3)
+            .map(|fft_pass_number| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                FrequencyPeak {
+                    fft_pass_number,
+                    peak_magnitude: 8000,
+                    corrected_peak_frequency_bin: ((32 + state % 480) * 64) as u16,
+                    sample_rate_hz: 16000,
+                }
+            })
+            .collect();
+        HashMap::from([(FrequencyBand::_520_1450, frequency_peaks)])
+    }
+
+    fn track_names(index: &FingerprintIndex) -> Vec<(u32, String)> {
+        index.tracks().into_iter().map(|track| (track.id, track.name.clone())).collect()
+    }
+
+    fn temporary_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("shazamio-core-{}-{}.idx", name, std::process::id()))
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let mut index = FingerprintIndex::new();
+        index.add_track("first", &peaks(1), 16000).unwrap();
+        let second = index.add_track("second ✓", &peaks(2), 16000).unwrap();
+        index.remove_track(second);
+        index.add_track("third", &peaks(3), 16000).unwrap();
+
+        let loaded = FingerprintIndex::from_bytes(index.to_bytes()).unwrap();
+        assert_eq!(track_names(&loaded), vec![(0, "first".to_string()), (2, "third".to_string())]);
+        assert!(!loaded.has_unsaved_changes());
+        let matches = loaded.query(&peaks(3)).unwrap();
+        assert_eq!(matches[0].track_id, 2);
+        assert_eq!(matches[0].offset_ms, 0);
+        assert!(loaded.query(&peaks(2)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_checksum_mismatch() {
+        let mut index = FingerprintIndex::new();
+        index.add_track("first", &peaks(1), 16000).unwrap();
+        let mut bytes = index.to_bytes();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let error = FingerprintIndex::from_bytes(bytes).err().unwrap();
+        assert_eq!(error.to_string(), "Invalid index segment checksum");
+
+        let mut bytes = index.to_bytes();
+        bytes[8] ^= 1;
+        assert!(FingerprintIndex::from_bytes(bytes).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_segments() {
+        let mut index = FingerprintIndex::new();
+        index.add_track("first", &peaks(1), 16000).unwrap();
+        let bytes = index.to_bytes();
+        for length in [HEADER_SIZE + 4, HEADER_SIZE + 12, HEADER_SIZE + 20, bytes.len() - 1] {
+            assert!(FingerprintIndex::from_bytes(bytes[..length].to_vec()).is_err(), "length {}", length);
+        }
+    }
+
+    #[test]
+    fn rejects_counts_beyond_the_segment() {
+        // A tracks segment claiming a huge name and one claiming more landmarks than it holds
+        for body in [
+            [1u32, 0, 0, u32::MAX - 2, 0].iter().flat_map(|value| value.to_le_bytes()).collect::<Vec<u8>>(),
+            [0u32, (u32::MAX / LANDMARK_SIZE as u32) + 1].iter().flat_map(|value| value.to_le_bytes()).collect(),
+        ] {
+            let mut bytes = encode_header(0);
+            bytes.extend(encode_segment(TRACKS_SEGMENT, body));
+            assert_eq!(FingerprintIndex::from_bytes(bytes).err().unwrap().to_string(), "Invalid index tracks segment");
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn saves_after_compacting() {
+        let path = temporary_path("compact");
+        let _ = std::fs::remove_file(&path);
+
+        let mut index = FingerprintIndex::new();
+        index.add_track("first", &peaks(1), 16000).unwrap();
+        let second = index.add_track("second", &peaks(2), 16000).unwrap();
+        index.save(&path).unwrap();
+        index.remove_track(second);
+        index.save(&path).unwrap();
+
+        index.compact();
+        assert!(index.has_unsaved_changes());
+        index.save(&path).unwrap();
+        assert!(!index.has_unsaved_changes());
+
+        let loaded = FingerprintIndex::open(&path).unwrap();
+        assert_eq!(track_names(&loaded), vec![(0, "first".to_string())]);
+        assert_eq!(loaded.segments.len(), 1);
+        assert_eq!(loaded.query(&peaks(1)).unwrap()[0].track_id, 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn never_reuses_ids_of_compacted_tracks() {
+        let path = temporary_path("reuse");
+        let _ = std::fs::remove_file(&path);
+
+        let mut index = FingerprintIndex::new();
+        index.add_track("first", &peaks(1), 16000).unwrap();
+        let second = index.add_track("second", &peaks(2), 16000).unwrap();
+        index.remove_track(second);
+        index.compact();
+        index.save(&path).unwrap();
+
+        for mut loaded in [FingerprintIndex::open(&path).unwrap(), FingerprintIndex::from_bytes(index.to_bytes()).unwrap()] {
+            assert_eq!(loaded.add_track("third", &peaks(3), 16000).unwrap(), second + 1);
+            assert_eq!(loaded.query(&peaks(2)).unwrap().len(), 0);
+        }
+
+        // Appended segments still count once the header is behind them
+        let mut loaded = FingerprintIndex::open(&path).unwrap();
+        let third = loaded.add_track("third", &peaks(3), 16000).unwrap();
+        loaded.save(&path).unwrap();
+        assert_eq!(FingerprintIndex::open(&path).unwrap().add_track("fourth", &peaks(4), 16000).unwrap(), third + 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_peaks_of_other_sample_rates() {
+        let mut index = FingerprintIndex::new();
+        index.add_track("first", &peaks(1), 16000).unwrap();
+        let mut telephone = peaks(1);
+        for peak in telephone.values_mut().flatten() {
+            peak.sample_rate_hz = 8000;
+        }
+        assert!(index.query(&telephone).unwrap_err().to_string().contains("8000 Hz"));
+        assert!(index.add_track("second", &telephone, 16000).is_err());
+        assert_eq!(index.tracks().len(), 1);
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+
+use crate::fingerprinting::signature_generator::{FrequencyBand, FrequencyPeak};
+
+/// Number of later peaks each anchor peak is paired with
+const FAN_OUT: usize = 5;
+
+/// Largest time difference between the two peaks of a landmark, in FFT passes (~0.5s)
+const MAX_PASS_DELTA: u32 = 63;
+
+/// Largest frequency difference between the two peaks of a landmark, in FFT bins
+const MAX_BIN_DELTA: i32 = 128;
+
+/// A pair of nearby peaks hashed from their frequencies and time difference,
+/// anchored at the FFT pass of the first peak
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Landmark {
+    pub hash: u32,
+    pub fft_pass_number: u32,
+}
+
+impl Landmark {
+    fn new(anchor: (u32, u32), target: (u32, u32)) -> Landmark {
+        let pass_delta = target.0 - anchor.0;
+        Landmark {
+            // 10 bits anchor bin, 10 bits target bin, 6 bits time difference
+            hash: (anchor.1 << 16) | (target.1 << 6) | pass_delta,
+            fft_pass_number: anchor.0,
+        }
+    }
+
+    /// The landmark's hash and the hashes with its time difference one FFT pass
+    /// either side, so peaks shifted across a pass boundary still match
+    pub fn tolerant_hashes(&self) -> impl Iterator<Item = u32> {
+        let pass_delta = self.hash & 0x3f;
+        let hash = self.hash & !0x3f;
+        [pass_delta, pass_delta - 1, pass_delta + 1]
+            .into_iter()
+            .filter(|pass_delta| (1..=MAX_PASS_DELTA).contains(pass_delta))
+            .map(move |pass_delta| hash | pass_delta)
+    }
+}
+
+/// Pairs every peak with the next few peaks close to it in time and frequency.
+pub fn landmarks_from_peaks(frequency_band_to_sound_peaks: &HashMap<FrequencyBand, Vec<FrequencyPeak>>) -> Vec<Landmark> {
+    // (fft pass number, FFT bin) of every peak across all bands, in time order
+    let mut peaks: Vec<(u32, u32)> = frequency_band_to_sound_peaks
+        .values()
+        .flatten()
+        .map(|peak| (peak.fft_pass_number, (peak.corrected_peak_frequency_bin >> 6) as u32))
+        .collect();
+    peaks.sort_unstable();
+
+    let mut landmarks = Vec::with_capacity(peaks.len() * FAN_OUT);
+    for (index, anchor) in peaks.iter().enumerate() {
+        let targets = peaks[index + 1..]
+            .iter()
+            .take_while(|target| target.0 - anchor.0 <= MAX_PASS_DELTA)
+            .filter(|target| target.0 > anchor.0 && (target.1 as i32 - anchor.1 as i32).abs() <= MAX_BIN_DELTA)
+            .take(FAN_OUT);
+        for target in targets {
+            landmarks.push(Landmark::new(*anchor, *target));
+        }
+    }
+    landmarks
+}
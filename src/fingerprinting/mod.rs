@@ -1,5 +1,25 @@
 pub mod hanning;
 pub mod decoded_signature;
+pub mod progress;
+pub mod probe;
 pub mod samples_from_bytes;
 pub mod resample;
-pub mod signature_generator;
\ No newline at end of file
+pub mod downmix;
+pub mod gain;
+pub mod preprocess;
+pub mod signature_generator;
+pub mod compare;
+pub mod sync;
+pub mod landmarks;
+pub mod index;
+pub mod catalogue;
+pub mod skew;
+pub mod tracklist;
+pub mod repeats;
+pub mod streaming;
+pub mod window_selection;
+pub mod quality;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod monitor;
+#[cfg(test)]
+mod test_audio;
\ No newline at end of file
@@ -0,0 +1,627 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use symphonia::core::audio::{Channels, SampleBuffer};
+use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::{MediaSourceStream, ReadOnlySource};
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use crate::fingerprinting::decoded_signature::{check_sample_rate, DecodedSignature, DEFAULT_SAMPLE_RATE};
+use crate::fingerprinting::downmix::Downmix;
+
+pub struct MonitorOptions {
+    /// Seconds of audio between two signatures
+    pub interval_seconds: u32,
+    /// Seconds of audio in each signature
+    pub signature_seconds: u32,
+    /// Seconds of recent audio kept in the ring buffer, at least `signature_seconds`
+    pub ring_seconds: u32,
+    /// How multichannel audio is mixed to mono
+    pub downmix: Downmix,
+    /// Rate signatures are generated at, one of `SAMPLE_RATES`, 16 KHz when unset
+    pub sample_rate: Option<u32>,
+}
+
+impl Default for MonitorOptions {
+    fn default() -> Self {
+        MonitorOptions {
+            interval_seconds: 12,
+            signature_seconds: 12,
+            ring_seconds: 60,
+            downmix: Downmix::Average,
+            sample_rate: None,
+        }
+    }
+}
+
+/// A signature emitted by a `StreamMonitor`
+pub struct MonitorSignature {
+    /// Wall-clock time the audio of the signature started at
+    pub timestamp: SystemTime,
+    /// Position in the stream the audio of the signature started at
+    pub stream_offset_ms: u64,
+    /// Last title announced by an ICY stream
+    pub stream_title: Option<String>,
+    pub signature: DecodedSignature,
+}
+
+/// Where a `StreamMonitor` reads audio from
+pub enum MonitorInput {
+    /// Interleaved signed 16-bit little-endian PCM
+    Pcm {
+        reader: Box<dyn Read + Send + Sync>,
+        sample_rate: u32,
+        channels: usize,
+    },
+    /// Any streamable container symphonia can decode, such as MP3, ADTS AAC or Ogg
+    Encoded {
+        reader: Box<dyn Read + Send + Sync>,
+        stream_title: Option<Arc<Mutex<Option<String>>>>,
+    },
+}
+
+/// Checks the format of raw PCM, which can't be read from the data itself
+fn check_pcm_format(sample_rate: u32, channel_count: usize) -> io::Result<()> {
+    if sample_rate == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "PCM sample rate must be above 0 Hz"));
+    }
+    if !(1..=MAX_PCM_CHANNELS).contains(&channel_count) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("PCM must have between 1 and {} channels", MAX_PCM_CHANNELS)));
+    }
+    Ok(())
+}
+
+impl MonitorInput {
+    /// Raw PCM piped to stdin, an error when the sample rate or channel count is 0.
+    pub fn stdin_pcm(sample_rate: u32, channels: usize) -> io::Result<MonitorInput> {
+        check_pcm_format(sample_rate, channels)?;
+        Ok(MonitorInput::Pcm { reader: Box::new(io::stdin()), sample_rate, channels })
+    }
+
+    /// A file that keeps being appended to, read from its end like `tail -f`. Encoded files
+    /// must then be in a container that can be joined mid-stream, such as MP3 or ADTS AAC.
+    /// With `from_start` the audio already in the file is read first, faster than real time,
+    /// so timestamps should be anchored with `StreamMonitor::with_start_time`.
+    pub fn follow_file(path: &Path, pcm: Option<(u32, usize)>, from_start: bool) -> io::Result<MonitorInput> {
+        if let Some((sample_rate, channels)) = pcm {
+            check_pcm_format(sample_rate, channels)?;
+        }
+        let mut file = File::open(path)?;
+        let position = if from_start { 0 } else { file.seek(SeekFrom::End(0))? };
+        let reader = Box::new(FollowReader { file, position });
+        Ok(match pcm {
+            Some((sample_rate, channels)) => MonitorInput::Pcm { reader, sample_rate, channels },
+            None => MonitorInput::Encoded { reader, stream_title: None },
+        })
+    }
+
+    /// An HTTP or ICY (SHOUTcast/Icecast) stream.
+    pub fn http(url: &str) -> Result<MonitorInput, Box<dyn Error>> {
+        let stream_title = Arc::new(Mutex::new(None));
+        let reader = IcyReader::connect(url, stream_title.clone())?;
+        Ok(MonitorInput::Encoded { reader: Box::new(reader), stream_title: Some(stream_title) })
+    }
+}
+
+/// Error returned once by a `FollowReader` when its file was truncated and it started over,
+/// so the container can be probed again
+#[derive(Debug)]
+struct Truncated;
+
+impl fmt::Display for Truncated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Followed file was truncated")
+    }
+}
+
+impl Error for Truncated {}
+
+fn is_truncated(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|inner| inner.is::<Truncated>())
+}
+
+/// Reads a file that is still being written, waiting for more data at its end
+struct FollowReader {
+    file: File,
+    position: u64,
+}
+
+impl Read for FollowReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.file.read(buf)?;
+            if read > 0 {
+                self.position += read as u64;
+                return Ok(read);
+            }
+            // Start over if the file was truncated, as when a recorder rotates its output
+            if self.file.metadata()?.len() < self.position {
+                self.position = self.file.seek(SeekFrom::Start(0))?;
+                return Err(io::Error::other(Truncated));
+            }
+            thread::sleep(Duration::from_millis(250));
+        }
+    }
+}
+
+/// Reader shared between the successive probes of an encoded input
+#[derive(Clone)]
+struct SharedReader(Arc<Mutex<Box<dyn Read + Send + Sync>>>);
+
+impl Read for SharedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+}
+
+/// Reads the audio of an HTTP stream, removing the ICY metadata interleaved in it
+struct IcyReader {
+    stream: Box<dyn BufRead + Send + Sync>,
+    metadata_interval: Option<usize>,
+    until_metadata: usize,
+    stream_title: Arc<Mutex<Option<String>>>,
+}
+
+impl IcyReader {
+    fn connect(url: &str, stream_title: Arc<Mutex<Option<String>>>) -> Result<IcyReader, Box<dyn Error>> {
+        let address = url.strip_prefix("http://").ok_or("Only http:// streams are supported")?;
+        let (host, path) = match address.find('/') {
+            Some(index) => (&address[..index], &address[index..]),
+            None => (address, "/"),
+        };
+        let host_with_port = if host.contains(':') { host.to_string() } else { format!("{}:80", host) };
+
+        let mut stream = TcpStream::connect(host_with_port)?;
+        write!(
+            stream,
+            "GET {} HTTP/1.0\r\nHost: {}\r\nIcy-MetaData: 1\r\nUser-Agent: shazamio-core\r\nConnection: close\r\n\r\n",
+            path, host
+        )?;
+
+        IcyReader::from_response(Box::new(BufReader::new(stream)), stream_title)
+    }
+
+    /// Reads the status line and headers of a response, leaving `stream` at the start of the body.
+    fn from_response(mut stream: Box<dyn BufRead + Send + Sync>, stream_title: Arc<Mutex<Option<String>>>) -> Result<IcyReader, Box<dyn Error>> {
+        let mut status = String::new();
+        stream.read_line(&mut status)?;
+        if status.split_whitespace().nth(1) != Some("200") {
+            return Err(format!("Stream responded with {}", status.trim()).into());
+        }
+
+        let mut metadata_interval = None;
+        loop {
+            let mut header = String::new();
+            if stream.read_line(&mut header)? == 0 || header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("icy-metaint") {
+                    metadata_interval = value.trim().parse().ok().filter(|interval| *interval > 0);
+                }
+            }
+        }
+
+        Ok(IcyReader {
+            stream,
+            metadata_interval,
+            until_metadata: metadata_interval.unwrap_or(0),
+            stream_title,
+        })
+    }
+
+    fn read_metadata(&mut self) -> io::Result<()> {
+        let mut length = [0u8; 1];
+        self.stream.read_exact(&mut length)?;
+        let mut metadata = vec![0u8; length[0] as usize * 16];
+        self.stream.read_exact(&mut metadata)?;
+        if let Some(title) = parse_stream_title(&metadata) {
+            *self.stream_title.lock().unwrap() = Some(title);
+        }
+        Ok(())
+    }
+}
+
+impl Read for IcyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(metadata_interval) = self.metadata_interval else {
+            return self.stream.read(buf);
+        };
+        if self.until_metadata == 0 {
+            self.read_metadata()?;
+            self.until_metadata = metadata_interval;
+        }
+        let length = buf.len().min(self.until_metadata);
+        let read = self.stream.read(&mut buf[..length])?;
+        self.until_metadata -= read;
+        Ok(read)
+    }
+}
+
+/// Title of an ICY metadata block, which looks like StreamTitle='Artist - Title';StreamUrl='';
+fn parse_stream_title(metadata: &[u8]) -> Option<String> {
+    let metadata = String::from_utf8_lossy(metadata);
+    let start = metadata.find("StreamTitle='")?;
+    let title = &metadata[start + 13..];
+    Some(title[..title.find("';").unwrap_or(title.trim_end_matches('\0').len())].to_string())
+}
+
+/// Most channels raw PCM can have, as many as there are channel positions
+const MAX_PCM_CHANNELS: usize = 32;
+
+/// Layout of raw PCM with `channel_count` channels, in the WAVE order symphonia uses
+fn pcm_layout(channel_count: usize) -> Channels {
+    match channel_count {
+        1 => Channels::FRONT_CENTRE,
+        _ => Channels::from_bits_truncate(((1u64 << channel_count.min(MAX_PCM_CHANNELS)) - 1) as u32),
+    }
+}
+
+/// RFC 3339 UTC timestamp with millisecond precision
+fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, seconds_of_day) = ((seconds / 86400) as i64, seconds % 86400);
+
+    // Civil date from days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Fingerprints a never-ending input, emitting a signature of the most recent
+/// audio at a fixed interval while keeping only a bounded ring of samples.
+pub struct StreamMonitor {
+    options: MonitorOptions,
+    log: Option<Box<dyn Write + Send>>,
+    start_time: Option<SystemTime>,
+    ring: VecDeque<f32>,
+    sample_rate: u32,
+    channels: Channels,
+    samples_since_signature: usize,
+    total_samples: u64,
+}
+
+impl StreamMonitor {
+    pub fn new(options: MonitorOptions) -> StreamMonitor {
+        StreamMonitor {
+            options,
+            log: None,
+            start_time: None,
+            ring: VecDeque::new(),
+            sample_rate: 0,
+            channels: Channels::empty(),
+            samples_since_signature: 0,
+            total_samples: 0,
+        }
+    }
+
+    /// Writes a line for every emitted signature to `log`: timestamp, stream
+    /// offset in ms, duration in ms, stream title and signature uri, tab separated.
+    pub fn with_log(mut self, log: Box<dyn Write + Send>) -> StreamMonitor {
+        self.log = Some(log);
+        self
+    }
+
+    /// Timestamps signatures at `start_time` plus their stream offset, for inputs that are read
+    /// faster than real time such as a recording followed from its start. Otherwise signatures
+    /// are timestamped when they are emitted, minus their duration.
+    pub fn with_start_time(mut self, start_time: SystemTime) -> StreamMonitor {
+        self.start_time = Some(start_time);
+        self
+    }
+
+    /// Drops the samples in the ring, when the input jumped and they can't be joined to the next ones.
+    fn restart(&mut self) {
+        self.ring.clear();
+        self.samples_since_signature = 0;
+    }
+
+    /// Rate signatures are generated at, an error when the options ask for an unsupported one
+    fn signature_rate(&self) -> Result<u32, Box<dyn Error>> {
+        check_sample_rate(self.options.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE))
+    }
+
+    /// Adds interleaved samples laid out as `channels` to the ring, returning the signatures
+    /// completed by them, or an error when the layout lacks the channel `downmix` picks or is empty.
+    pub fn push_samples(&mut self, samples: &[f32], sample_rate: u32, channels: Channels) -> Result<Vec<MonitorSignature>, Box<dyn Error>> {
+        let signature_rate = self.signature_rate()?;
+        check_pcm_format(sample_rate, channels.count())?;
+        if sample_rate != self.sample_rate || channels != self.channels {
+            // The stream changed format, samples in the ring can't be mixed with the new ones
+            self.restart();
+            self.sample_rate = sample_rate;
+            self.channels = channels;
+        }
+
+        let samples_per_second = sample_rate as usize * channels.count();
+        let ring_capacity = self.options.ring_seconds.max(self.options.signature_seconds) as usize * samples_per_second;
+        let interval = self.options.interval_seconds.max(1) as usize * samples_per_second;
+        let signature_length = self.options.signature_seconds as usize * samples_per_second;
+
+        let mut signatures = Vec::new();
+        for chunk in samples.chunks(interval) {
+            let until_signature = interval - self.samples_since_signature;
+            let (before, after) = chunk.split_at(until_signature.min(chunk.len()));
+            self.extend_ring(before, ring_capacity);
+            self.samples_since_signature += before.len();
+
+            if self.samples_since_signature == interval {
+                self.samples_since_signature = 0;
+                let length = signature_length.min(self.ring.len());
+                let window: Vec<f32> = self.ring.range(self.ring.len() - length..).copied().collect();
+                let end_samples = self.total_samples + before.len() as u64;
+                let start_ms = (end_samples - length as u64) * 1000 / samples_per_second as u64;
+                let duration = Duration::from_millis(length as u64 * 1000 / samples_per_second as u64);
+                let timestamp = match self.start_time {
+                    Some(start_time) => start_time + Duration::from_millis(start_ms),
+                    None => SystemTime::now() - duration,
+                };
+                signatures.push(MonitorSignature {
+                    timestamp,
+                    stream_offset_ms: start_ms,
+                    stream_title: None,
+                    signature: DecodedSignature::from_samples(self.options.downmix.apply(&window, channels)?, sample_rate, 1, signature_rate),
+                });
+            }
+
+            self.extend_ring(after, ring_capacity);
+            self.samples_since_signature += after.len();
+            self.total_samples += chunk.len() as u64;
+        }
+        Ok(signatures)
+    }
+
+    fn extend_ring(&mut self, samples: &[f32], ring_capacity: usize) {
+        self.ring.extend(samples);
+        let excess = self.ring.len().saturating_sub(ring_capacity);
+        self.ring.drain(..excess);
+    }
+
+    fn emit(
+        &mut self,
+        signatures: Vec<MonitorSignature>,
+        stream_title: &Option<Arc<Mutex<Option<String>>>>,
+        on_signature: &mut impl FnMut(MonitorSignature) -> bool,
+    ) -> Result<bool, Box<dyn Error>> {
+        for mut signature in signatures {
+            signature.stream_title = stream_title.as_ref().and_then(|title| title.lock().unwrap().clone());
+            if let Some(log) = &mut self.log {
+                writeln!(
+                    log,
+                    "{}\t{}\t{}\t{}\t{}",
+                    format_timestamp(signature.timestamp),
+                    signature.stream_offset_ms,
+                    signature.signature.samplems(),
+                    signature.stream_title.as_deref().unwrap_or(""),
+                    signature.signature.uri()
+                )?;
+                log.flush()?;
+            }
+            if !on_signature(signature) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Reads `input` until it ends or `on_signature` returns false, calling
+    /// `on_signature` with every signature emitted.
+    pub fn run(&mut self, input: MonitorInput, mut on_signature: impl FnMut(MonitorSignature) -> bool) -> Result<(), Box<dyn Error>> {
+        self.signature_rate()?;
+        match input {
+            MonitorInput::Pcm { mut reader, sample_rate, channels } => {
+                check_pcm_format(sample_rate, channels)?;
+                let mut bytes = vec![0u8; 8192];
+                let mut pending = 0;
+                loop {
+                    let read = match reader.read(&mut bytes[pending..]) {
+                        Ok(read) => read,
+                        Err(err) if is_truncated(&err) => {
+                            pending = 0;
+                            self.restart();
+                            continue;
+                        }
+                        Err(err) => return Err(err.into()),
+                    };
+                    if read == 0 {
+                        return Ok(());
+                    }
+                    let available = pending + read;
+                    let whole = available - available % 2;
+                    let samples: Vec<f32> = bytes[..whole]
+                        .chunks_exact(2)
+                        .map(|sample| i16::from_le_bytes([sample[0], sample[1]]) as f32 / i16::MAX as f32)
+                        .collect();
+                    bytes.copy_within(whole..available, 0);
+                    pending = available - whole;
+
+                    let signatures = self.push_samples(&samples, sample_rate, pcm_layout(channels))?;
+                    if !self.emit(signatures, &None, &mut on_signature)? {
+                        return Ok(());
+                    }
+                }
+            }
+            MonitorInput::Encoded { reader, stream_title } => {
+                let reader = SharedReader(Arc::new(Mutex::new(reader)));
+                // Probed again from the same reader whenever a followed file is truncated
+                while self.decode_encoded(reader.clone(), &stream_title, &mut on_signature)? {
+                    self.restart();
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Decodes an encoded input until it ends, `on_signature` returns false or, returning true,
+    /// the followed file is truncated and has to be probed again.
+    fn decode_encoded(
+        &mut self,
+        reader: SharedReader,
+        stream_title: &Option<Arc<Mutex<Option<String>>>>,
+        on_signature: &mut impl FnMut(MonitorSignature) -> bool,
+    ) -> Result<bool, Box<dyn Error>> {
+        let mss = MediaSourceStream::new(Box::new(ReadOnlySource::new(reader)), Default::default());
+        let probe_result = match symphonia::default::get_probe().format(&Hint::new(), mss, &FormatOptions::default(), &MetadataOptions::default()) {
+            Ok(probe_result) => probe_result,
+            Err(SymphoniaError::IoError(err)) if is_truncated(&err) => return Ok(true),
+            Err(err) => return Err(err.into()),
+        };
+        let mut format = probe_result.format;
+        let track = format
+            .tracks()
+            .iter()
+            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or(SymphoniaError::Unsupported("codec"))?;
+        let mut decoder = symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+        let track_id = track.id;
+
+        loop {
+            let packet = match format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(err)) if is_truncated(&err) => return Ok(true),
+                Err(SymphoniaError::IoError(err)) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+                Err(err) => return Err(err.into()),
+            };
+            if packet.track_id() != track_id {
+                continue;
+            }
+            let audio_buf = match decoder.decode(&packet) {
+                Ok(audio_buf) => audio_buf,
+                // A live stream can start or glitch mid-frame, skip the packet
+                Err(SymphoniaError::DecodeError(_)) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            let spec = *audio_buf.spec();
+            let mut sample_buf = SampleBuffer::<f32>::new(audio_buf.capacity() as u64, spec);
+            sample_buf.copy_interleaved_ref(audio_buf);
+
+            let signatures = self.push_samples(sample_buf.samples(), spec.rate, spec.channels)?;
+            if !self.emit(signatures, stream_title, on_signature)? {
+                return Ok(false);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::fingerprinting::signature_generator::peaks_sample_rate;
+    use crate::fingerprinting::test_audio::noise;
+
+    fn icy_reader(response: &[u8], stream_title: &Arc<Mutex<Option<String>>>) -> Result<IcyReader, Box<dyn Error>> {
+        IcyReader::from_response(Box::new(Cursor::new(response.to_vec())), stream_title.clone())
+    }
+
+    #[test]
+    fn strips_icy_metadata_at_its_interval() {
+        let mut response = b"ICY 200 OK\r\nicy-name: Radio\r\nIcy-MetaInt: 4\r\n\r\n".to_vec();
+        response.extend_from_slice(b"abcd\x02StreamTitle='A - B';\0\0\0\0\0\0\0\0\0\0\0\0efgh\x00ij");
+        let stream_title = Arc::new(Mutex::new(None));
+        let mut reader = icy_reader(&response, &stream_title).unwrap();
+        assert_eq!(reader.metadata_interval, Some(4));
+
+        let mut audio = Vec::new();
+        reader.read_to_end(&mut audio).unwrap();
+        assert_eq!(audio, b"abcdefghij");
+        assert_eq!(stream_title.lock().unwrap().as_deref(), Some("A - B"));
+    }
+
+    #[test]
+    fn reads_streams_without_icy_metadata() {
+        for header in ["", "icy-metaint: 0\r\n", "icy-metaint: many\r\n"] {
+            let response = format!("HTTP/1.0 200 OK\r\n{}\r\nabcdef", header);
+            let mut reader = icy_reader(response.as_bytes(), &Arc::default()).unwrap();
+            assert_eq!(reader.metadata_interval, None);
+
+            let mut audio = Vec::new();
+            reader.read_to_end(&mut audio).unwrap();
+            assert_eq!(audio, b"abcdef");
+        }
+    }
+
+    #[test]
+    fn rejects_unsuccessful_responses() {
+        assert!(icy_reader(b"HTTP/1.0 404 Not Found\r\n\r\n", &Arc::default()).is_err());
+    }
+
+    #[test]
+    fn parses_stream_titles() {
+        assert_eq!(parse_stream_title(b"StreamTitle='Artist - It's';StreamUrl='';").as_deref(), Some("Artist - It's"));
+        assert_eq!(parse_stream_title(b"StreamTitle='Unterminated\0\0").as_deref(), Some("Unterminated"));
+        assert_eq!(parse_stream_title(b"StreamUrl='';\0"), None);
+    }
+
+    #[test]
+    fn formats_rfc3339_timestamps() {
+        assert_eq!(format_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(format_timestamp(UNIX_EPOCH + Duration::from_millis(951_825_599_999)), "2000-02-29T11:59:59.999Z");
+        assert_eq!(format_timestamp(UNIX_EPOCH + Duration::from_secs(4_107_542_400)), "2100-03-01T00:00:00.000Z");
+        assert_eq!(format_timestamp(UNIX_EPOCH + Duration::from_millis(1_704_067_199_123)), "2023-12-31T23:59:59.123Z");
+    }
+
+    #[test]
+    fn timestamps_from_the_start_time() {
+        let options = MonitorOptions { interval_seconds: 1, signature_seconds: 1, ring_seconds: 1, ..Default::default() };
+        let start_time = UNIX_EPOCH + Duration::from_secs(1000);
+        let mut monitor = StreamMonitor::new(options).with_start_time(start_time);
+        let signatures = monitor.push_samples(&vec![0.0; 16000 * 3], 16000, Channels::FRONT_CENTRE).unwrap();
+        let timestamps: Vec<SystemTime> = signatures.iter().map(|signature| signature.timestamp).collect();
+        assert_eq!(timestamps, [0, 1, 2].map(|second| start_time + Duration::from_secs(second)));
+    }
+
+    #[test]
+    fn rejects_pcm_without_samples_or_channels() {
+        for (sample_rate, channels) in [(0, 2), (44100, 0), (44100, 33)] {
+            assert!(MonitorInput::stdin_pcm(sample_rate, channels).is_err());
+            let input = MonitorInput::Pcm { reader: Box::new(Cursor::new(vec![0u8; 1024])), sample_rate, channels };
+            let error = StreamMonitor::new(MonitorOptions::default()).run(input, |_| true).err().unwrap();
+            assert_eq!(error.downcast::<io::Error>().unwrap().kind(), io::ErrorKind::InvalidInput);
+        }
+        let mut monitor = StreamMonitor::new(MonitorOptions::default());
+        assert!(monitor.push_samples(&[0.0; 64], 16000, Channels::empty()).is_err());
+        assert!(monitor.push_samples(&[0.0; 64], 0, Channels::FRONT_CENTRE).is_err());
+    }
+
+    #[test]
+    fn generates_signatures_at_the_sample_rate_asked_for() {
+        let options = MonitorOptions { interval_seconds: 1, signature_seconds: 1, ring_seconds: 1, sample_rate: Some(8000), ..Default::default() };
+        let mut signatures = StreamMonitor::new(options).push_samples(&noise(1, 44100, 0.3), 44100, Channels::FRONT_CENTRE).unwrap();
+        assert_eq!(signatures.len(), 1);
+        assert_eq!(peaks_sample_rate(signatures[0].signature.frequency_band_to_sound_peaks()), Some(8000));
+
+        let options = MonitorOptions { sample_rate: Some(22050), ..Default::default() };
+        assert!(StreamMonitor::new(options).push_samples(&[0.0; 64], 44100, Channels::FRONT_CENTRE).is_err());
+    }
+}
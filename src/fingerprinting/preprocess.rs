@@ -0,0 +1,325 @@
+use std::f32::consts::PI;
+use std::fmt;
+use std::sync::Arc;
+
+use chfft::RFft1D;
+
+/// A filter applied to the mono signal, once resampled to the signature's sample rate,
+/// before it is fingerprinted. Filters keep no state between calls, so one chain can be
+/// shared by every recognition.
+pub trait Filter: Send + Sync {
+    /// Short name, such as "high-pass"
+    fn name(&self) -> &str;
+
+    /// Filters the samples in place.
+    fn process(&self, samples: &mut [f32], sample_rate: u32);
+}
+
+/// Filters applied in order
+#[derive(Clone, Default)]
+pub struct FilterChain {
+    filters: Vec<Arc<dyn Filter>>,
+}
+
+impl FilterChain {
+    pub fn new() -> FilterChain {
+        FilterChain::default()
+    }
+
+    /// Adds a filter to the end of the chain.
+    pub fn push(&mut self, filter: impl Filter + 'static) -> &mut FilterChain {
+        self.filters.push(Arc::new(filter));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    pub fn process(&self, samples: &mut [f32], sample_rate: u32) {
+        for filter in &self.filters {
+            filter.process(samples, sample_rate);
+        }
+    }
+}
+
+impl fmt::Debug for FilterChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.filters.iter().map(|filter| filter.name())).finish()
+    }
+}
+
+/// The built-in filters, applied in the order of the fields
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PreprocessOptions {
+    /// Remove any constant offset
+    pub dc_removal: bool,
+    /// Cutoff frequency of a high-pass filter, such as 100 Hz to remove rumble and handling noise
+    pub high_pass_hz: Option<f32>,
+    /// Attenuate frequencies that stay within this many dB of their noise floor, such as 6 dB
+    pub noise_gate_db: Option<f32>,
+    /// Pre-emphasis coefficient, such as 0.97, boosting high frequencies that phone captures lose
+    pub pre_emphasis: Option<f32>,
+}
+
+impl PreprocessOptions {
+    pub fn chain(&self) -> FilterChain {
+        let mut chain = FilterChain::new();
+        if self.dc_removal {
+            chain.push(DcRemoval);
+        }
+        if let Some(cutoff_hz) = self.high_pass_hz {
+            chain.push(HighPass { cutoff_hz });
+        }
+        if let Some(threshold_db) = self.noise_gate_db {
+            chain.push(NoiseGate { threshold_db, ..Default::default() });
+        }
+        if let Some(coefficient) = self.pre_emphasis {
+            chain.push(PreEmphasis { coefficient });
+        }
+        chain
+    }
+}
+
+/// One-pole DC blocker
+#[derive(Debug, Clone, Copy)]
+pub struct DcRemoval;
+
+impl Filter for DcRemoval {
+    fn name(&self) -> &str {
+        "dc-removal"
+    }
+
+    fn process(&self, samples: &mut [f32], sample_rate: u32) {
+        // Pole placed for a corner of about 5 Hz
+        let pole = 1.0 - 2.0 * PI * 5.0 / sample_rate as f32;
+        let (mut previous_input, mut previous_output) = (0f32, 0f32);
+        for sample in samples.iter_mut() {
+            let output = *sample - previous_input + pole * previous_output;
+            previous_input = *sample;
+            previous_output = output;
+            *sample = output;
+        }
+    }
+}
+
+/// Second order Butterworth high-pass filter
+#[derive(Debug, Clone, Copy)]
+pub struct HighPass {
+    pub cutoff_hz: f32,
+}
+
+impl Filter for HighPass {
+    fn name(&self) -> &str {
+        "high-pass"
+    }
+
+    fn process(&self, samples: &mut [f32], sample_rate: u32) {
+        let cutoff_hz = self.cutoff_hz.clamp(1.0, sample_rate as f32 * 0.45);
+        let omega = 2.0 * PI * cutoff_hz / sample_rate as f32;
+        let alpha = omega.sin() / std::f32::consts::SQRT_2;
+        let cos_omega = omega.cos();
+        let a0 = 1.0 + alpha;
+        let b0 = (1.0 + cos_omega) / 2.0 / a0;
+        let b1 = -(1.0 + cos_omega) / a0;
+        let b2 = b0;
+        let a1 = -2.0 * cos_omega / a0;
+        let a2 = (1.0 - alpha) / a0;
+
+        let (mut x1, mut x2, mut y1, mut y2) = (0f32, 0f32, 0f32, 0f32);
+        for sample in samples.iter_mut() {
+            let x0 = *sample;
+            let y0 = b0 * x0 + b1 * x1 + b2 * x2 - a1 * y1 - a2 * y2;
+            (x2, x1, y2, y1) = (x1, x0, y1, y0);
+            *sample = y0;
+        }
+    }
+}
+
+/// First order pre-emphasis, `y[n] = x[n] - coefficient * x[n - 1]`
+#[derive(Debug, Clone, Copy)]
+pub struct PreEmphasis {
+    pub coefficient: f32,
+}
+
+impl Filter for PreEmphasis {
+    fn name(&self) -> &str {
+        "pre-emphasis"
+    }
+
+    fn process(&self, samples: &mut [f32], _sample_rate: u32) {
+        let mut previous = 0f32;
+        for sample in samples.iter_mut() {
+            let input = *sample;
+            *sample = input - self.coefficient * previous;
+            previous = input;
+        }
+    }
+}
+
+/// Spectral noise gate: estimates the noise floor of every frequency bin as a low
+/// percentile of its magnitude over the whole signal, and attenuates the bins of
+/// each frame that don't rise `threshold_db` above it.
+#[derive(Debug, Clone, Copy)]
+pub struct NoiseGate {
+    pub threshold_db: f32,
+    /// How much gated bins are attenuated in dB
+    pub reduction_db: f32,
+    /// Percentile of a bin's magnitudes taken as its noise floor, between 0 and 1
+    pub floor_percentile: f32,
+}
+
+impl Default for NoiseGate {
+    fn default() -> Self {
+        NoiseGate { threshold_db: 6.0, reduction_db: 20.0, floor_percentile: 0.1 }
+    }
+}
+
+/// Frame length of the noise gate's short-time Fourier transform, 32 ms at 16 KHz
+const GATE_FRAME: usize = 512;
+const GATE_HOP: usize = GATE_FRAME / 2;
+
+impl Filter for NoiseGate {
+    fn name(&self) -> &str {
+        "noise-gate"
+    }
+
+    fn process(&self, samples: &mut [f32], _sample_rate: u32) {
+        if samples.len() < GATE_FRAME {
+            return;
+        }
+        // A square root Hann window on analysis and synthesis overlap-adds back to one at half frame hops
+        let window: Vec<f32> = (0..GATE_FRAME).map(|i| (PI * i as f32 / GATE_FRAME as f32).sin()).collect();
+        let mut fft = RFft1D::<f32>::new(GATE_FRAME);
+
+        // Pad so every sample is covered by two frames
+        let mut padded = vec![0f32; GATE_HOP];
+        padded.extend_from_slice(samples);
+        padded.resize(padded.len().div_ceil(GATE_HOP) * GATE_HOP + GATE_HOP, 0.0);
+
+        let spectra: Vec<_> = padded
+            .windows(GATE_FRAME)
+            .step_by(GATE_HOP)
+            .map(|frame| {
+                let windowed: Vec<f32> = frame.iter().zip(&window).map(|(sample, weight)| sample * weight).collect();
+                fft.forward0(&windowed)
+            })
+            .collect();
+
+        let bins = GATE_FRAME / 2 + 1;
+        let threshold = 10f32.powf(self.threshold_db / 20.0);
+        let reduction = 10f32.powf(-self.reduction_db.abs() / 20.0);
+        let floors: Vec<f32> = (0..bins)
+            .map(|bin| {
+                let mut magnitudes: Vec<f32> = spectra.iter().map(|spectrum| spectrum[bin].norm()).collect();
+                let index = ((magnitudes.len() - 1) as f32 * self.floor_percentile.clamp(0.0, 1.0)) as usize;
+                *magnitudes.select_nth_unstable_by(index, |a, b| a.total_cmp(b)).1
+            })
+            .collect();
+
+        let mut output = vec![0f32; padded.len()];
+        for (frame_index, mut spectrum) in spectra.into_iter().enumerate() {
+            for (value, floor) in spectrum.iter_mut().zip(&floors) {
+                if value.norm() < floor * threshold {
+                    *value *= reduction;
+                }
+            }
+            let frame = fft.backward(&spectrum);
+            let start = frame_index * GATE_HOP;
+            for (i, (sample, weight)) in frame.iter().zip(&window).enumerate() {
+                output[start + i] += sample * weight;
+            }
+        }
+        samples.copy_from_slice(&output[GATE_HOP..GATE_HOP + samples.len()]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprinting::test_audio::{noise, sine};
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    /// Gain in dB of a filter at `frequency`, measured once it has settled
+    fn response_db(filter: &impl Filter, frequency: f32, sample_rate: u32) -> f32 {
+        let mut samples = sine(frequency, sample_rate, sample_rate as usize * 2, 0.5);
+        filter.process(&mut samples, sample_rate);
+        let settled = &samples[sample_rate as usize..];
+        20.0 * (rms(settled) / (0.5 / std::f32::consts::SQRT_2)).log10()
+    }
+
+    fn assert_near(actual: f32, expected: f32, tolerance: f32) {
+        assert!((actual - expected).abs() < tolerance, "{} is not {}", actual, expected);
+    }
+
+    #[test]
+    fn removes_a_constant_offset() {
+        let mut samples: Vec<f32> = sine(440.0, 16000, 32000, 0.25).iter().map(|sample| sample + 0.5).collect();
+        DcRemoval.process(&mut samples, 16000);
+        let settled = &samples[16000..];
+        assert!((settled.iter().sum::<f32>() / settled.len() as f32).abs() < 1e-3);
+        assert_near(rms(settled), 0.25 / std::f32::consts::SQRT_2, 1e-3);
+    }
+
+    #[test]
+    fn attenuates_below_the_high_pass_cutoff_at_any_sample_rate() {
+        let high_pass = HighPass { cutoff_hz: 100.0 };
+        for sample_rate in [8000, 16000, 44100] {
+            // Second order: 3 dB down at the cutoff, then 12 dB an octave
+            assert_near(response_db(&high_pass, 100.0, sample_rate), -3.0, 0.2);
+            assert_near(response_db(&high_pass, 50.0, sample_rate), -12.3, 0.3);
+            assert_near(response_db(&high_pass, 25.0, sample_rate), -24.1, 0.5);
+            assert_near(response_db(&high_pass, 1000.0, sample_rate), 0.0, 0.1);
+        }
+    }
+
+    #[test]
+    fn pre_emphasizes_by_the_previous_sample() {
+        let mut samples = [1.0, 0.5, 0.0, -1.0];
+        PreEmphasis { coefficient: 0.9 }.process(&mut samples, 16000);
+        let expected = [1.0, -0.4, -0.45, -1.0];
+        samples.iter().zip(expected).for_each(|(actual, expected)| assert_near(*actual, expected, 1e-6));
+
+        let pre_emphasis = PreEmphasis { coefficient: 0.97 };
+        // |1 - 0.97 e^-jω|, from -26 dB at 100 Hz to +2.9 dB at a quarter of the sample rate
+        assert_near(response_db(&pre_emphasis, 100.0, 16000), -26.2, 0.3);
+        assert_near(response_db(&pre_emphasis, 4000.0, 16000), 2.9, 0.1);
+    }
+
+    #[test]
+    fn gates_noise_and_keeps_what_rises_above_it() {
+        // Steady noise, with a tone in the middle second
+        let mut samples = noise(1, 48000, 0.01);
+        for (sample, tone) in samples[16000..32000].iter_mut().zip(sine(1000.0, 16000, 16000, 0.5)) {
+            *sample += tone;
+        }
+        let (noise_before, tone_before) = (rms(&samples[2000..14000]), rms(&samples[18000..30000]));
+        // Steady noise rarely rises 20 dB above its own floor, unlike the tone
+        NoiseGate { threshold_db: 20.0, ..Default::default() }.process(&mut samples, 16000);
+
+        // The noise is brought down by close to the default 20 dB reduction, the tone is left
+        assert!(20.0 * (rms(&samples[2000..14000]) / noise_before).log10() < -15.0);
+        assert_near(20.0 * (rms(&samples[18000..30000]) / tone_before).log10(), 0.0, 0.5);
+
+        // Too short for a single frame
+        let mut short = noise(2, 100, 0.01);
+        let unfiltered = short.clone();
+        NoiseGate::default().process(&mut short, 16000);
+        assert_eq!(short, unfiltered);
+    }
+
+    #[test]
+    fn chains_the_filters_asked_for_in_order() {
+        assert!(PreprocessOptions::default().chain().is_empty());
+        let options = PreprocessOptions { dc_removal: true, high_pass_hz: Some(100.0), noise_gate_db: Some(6.0), pre_emphasis: Some(0.97) };
+        assert_eq!(format!("{:?}", options.chain()), r#"["dc-removal", "high-pass", "noise-gate", "pre-emphasis"]"#);
+
+        let mut samples = vec![0.5; 1000];
+        PreprocessOptions { dc_removal: true, pre_emphasis: Some(1.0), ..Default::default() }.chain().process(&mut samples, 16000);
+        assert!(samples[999].abs() < 0.01);
+    }
+}
+
@@ -0,0 +1,370 @@
+use std::io::{Cursor, Seek, SeekFrom};
+use std::sync::{Arc, OnceLock};
+
+use symphonia::core::codecs::{CodecParameters, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatOptions, FormatReader, Track};
+use symphonia::core::io::{MediaSourceStream, ReadBytes, SeekBuffered};
+use symphonia::core::meta::{MetadataOptions, MetadataRevision, StandardTagKey};
+use symphonia::core::probe::{Descriptor, Instantiate, Probe, QueryDescriptor};
+use symphonia::default::formats::{
+    AdtsReader, AiffReader, CafReader, FlacReader, IsoMp4Reader, MkvReader, MpaReader, OggReader, WavReader,
+};
+use symphonia_metadata::id3v2::Id3v2Reader;
+use wasm_bindgen::prelude::*;
+
+/// Song file bytes moved in once and shared, without copying them, by every probing
+/// attempt and the format reader, as symphonia only reads from owned sources
+#[derive(Debug, Clone)]
+pub struct SharedBytes(Arc<Vec<u8>>);
+
+impl From<Vec<u8>> for SharedBytes {
+    fn from(bytes: Vec<u8>) -> SharedBytes {
+        SharedBytes(Arc::new(bytes))
+    }
+}
+
+impl AsRef<[u8]> for SharedBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Which audio track of a multi-track container (commentary, languages) to decode
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum TrackSelection {
+    /// The first track with a known codec
+    #[default]
+    FirstDecodable,
+    /// The first decodable track too: symphonia doesn't expose the default flag of containers,
+    /// its own default track being the first track whatever its codec
+    Default,
+    /// Position in the container's track list
+    Index(usize),
+    Id(u32),
+    /// Language tag such as "eng" or "en-US", matched case-insensitively or by its primary subtag
+    Language(String),
+}
+
+/// Whether a track holds audio there is a decoder for. Symphonia leaves the codec of video
+/// and subtitle tracks unset.
+fn is_decodable(track: &Track) -> bool {
+    track.codec_params.codec != CODEC_TYPE_NULL && symphonia::default::get_codecs().get_codec(track.codec_params.codec).is_some()
+}
+
+impl TrackSelection {
+    pub fn select<'a>(&self, format: &'a dyn FormatReader) -> Result<&'a Track, Error> {
+        let tracks = format.tracks();
+        let track = match self {
+            TrackSelection::FirstDecodable | TrackSelection::Default => tracks.iter().find(|t| is_decodable(t)),
+            TrackSelection::Index(index) => tracks.get(*index),
+            TrackSelection::Id(id) => tracks.iter().find(|t| t.id == *id),
+            TrackSelection::Language(language) => tracks.iter().filter(|t| is_decodable(t)).find(|t| {
+                t.language.as_deref().is_some_and(|track_language| {
+                    let primary = |tag: &str| tag.split(['-', '_']).next().unwrap_or_default().to_ascii_lowercase();
+                    track_language.eq_ignore_ascii_case(language) || primary(track_language) == primary(language)
+                })
+            }),
+        };
+        match (self, track) {
+            (_, Some(track)) if is_decodable(track) => Ok(track),
+            // A track picked by position or id that isn't audio, rather than falling back to another
+            (_, Some(_)) => Err(Error::Unsupported("the selected track is not a decodable audio track")),
+            (TrackSelection::FirstDecodable | TrackSelection::Default, None) => Err(Error::Unsupported("codec")),
+            (_, None) => Err(Error::Unsupported("no track matches the track selection")),
+        }
+    }
+}
+
+/// A format reader along with what was learned while probing for it
+pub struct ProbedFormat {
+    pub format: Box<dyn FormatReader>,
+    /// Short name of the container format, such as "wave", "mp3" or "isomp4"
+    pub format_name: &'static str,
+    /// Metadata found before the container, such as ID3v2 tags
+    pub metadata: Vec<MetadataRevision>,
+}
+
+/// The format readers probed for, in the order symphonia's default probe registers them. The
+/// probe is built from this list too, so the formats found and the hints understood can't differ.
+const FORMAT_READERS: [fn() -> &'static [Descriptor]; 9] = [
+    AdtsReader::query,
+    CafReader::query,
+    FlacReader::query,
+    IsoMp4Reader::query,
+    MpaReader::query,
+    AiffReader::query,
+    WavReader::query,
+    OggReader::query,
+    MkvReader::query,
+];
+
+fn format_descriptors() -> impl Iterator<Item = &'static Descriptor> {
+    FORMAT_READERS.into_iter().flat_map(|query| query())
+}
+
+/// Probe for the format readers above and the ID3v2 tags that may come before them
+fn probe() -> &'static Probe {
+    static PROBE: OnceLock<Probe> = OnceLock::new();
+    PROBE.get_or_init(|| {
+        let mut probe = Probe::default();
+        format_descriptors().for_each(|descriptor| probe.register(descriptor));
+        probe.register_all::<Id3v2Reader>();
+        probe
+    })
+}
+
+/// Container format suggested by a file name or MIME type, for data that is
+/// ambiguous or has no header to probe for
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FormatHint {
+    /// File extension without the dot, such as "aac"
+    pub extension: Option<String>,
+    /// MIME type without parameters, such as "audio/aac"
+    pub mime_type: Option<String>,
+}
+
+impl FormatHint {
+    /// Hint from a file name, an extension (with or without the dot) or a MIME type.
+    pub fn parse(value: &str) -> FormatHint {
+        let value = value.trim().to_ascii_lowercase();
+        if value.contains('/') {
+            let mime_type = value.split(';').next().unwrap_or_default().trim().to_string();
+            return FormatHint { mime_type: Some(mime_type), ..Default::default() };
+        }
+        let extension = value.rsplit('.').next().unwrap_or_default().to_string();
+        FormatHint { extension: Some(extension), ..Default::default() }
+    }
+
+    /// Descriptor of the format reader the hint names
+    fn descriptor(&self) -> Option<&'static Descriptor> {
+        format_descriptors().find(|descriptor| {
+            self.extension.as_deref().is_some_and(|extension| descriptor.extensions.contains(&extension))
+                || self.mime_type.as_deref().is_some_and(|mime_type| descriptor.mime_types.contains(&mime_type))
+        })
+    }
+}
+
+/// Probes for a container format like symphonia's default probe, also reporting
+/// which format was found and keeping the metadata read on the way. The hinted
+/// format is tried first where the container starts, past any leading metadata,
+/// unless another format's marker is right there; the stream is scanned for a
+/// marker when it fails. Among formats sharing the marker found, the hint settles which.
+pub fn probe_format(open: impl Fn() -> MediaSourceStream, hint: &FormatHint) -> Result<ProbedFormat, Error> {
+    let hinted = hint.descriptor();
+    let mut tried_hinted = false;
+    let mut mss = open();
+    let mut metadata = Vec::new();
+    loop {
+        let start = mss.pos();
+        let probed = probe().next(&mut mss);
+        let marker_at_start = probed.is_ok() && mss.pos() == start;
+
+        // The stream is aligned to the marker the probe found, which names the format. Fewer
+        // bytes than markers are long may follow it at the end of the stream.
+        let mut context = [0u8; 16];
+        let mut context_len = 0;
+        if probed.is_ok() {
+            while context_len < context.len() {
+                match mss.read_buf(&mut context[context_len..])? {
+                    0 => break,
+                    read => context_len += read,
+                }
+            }
+            mss.seek_buffered_rev(context_len);
+        }
+        let context = &context[..context_len];
+        let has_marker = |descriptor: &&Descriptor| descriptor.markers.iter().any(|marker| context.starts_with(marker));
+
+        let try_hinted = match &probed {
+            Ok(Instantiate::Metadata(_)) if marker_at_start => false,
+            // Data that clearly is another format is decoded as that format
+            Ok(Instantiate::Format(_)) if marker_at_start => hinted.filter(has_marker).is_some(),
+            // Headerless data, such as raw ADTS AAC without a recognizable sync word
+            _ => true,
+        };
+        if try_hinted && !tried_hinted {
+            tried_hinted = true;
+            if let Some((descriptor, Instantiate::Format(instantiate))) = hinted.map(|descriptor| (descriptor, descriptor.inst)) {
+                let mut hinted_mss = open();
+                hinted_mss.seek(SeekFrom::Start(start))?;
+                if let Ok(format) = instantiate(hinted_mss, &FormatOptions::default()) {
+                    return Ok(ProbedFormat { format, format_name: descriptor.short_name, metadata });
+                }
+            }
+        }
+
+        match probed? {
+            Instantiate::Format(instantiate) => {
+                let (format_name, instantiate) = match hinted.filter(has_marker) {
+                    // Several formats share markers (MPEG audio layers), the hint settles which
+                    Some(descriptor) => match descriptor.inst {
+                        Instantiate::Format(hinted_instantiate) => (descriptor.short_name, hinted_instantiate),
+                        Instantiate::Metadata(_) => (descriptor.short_name, instantiate),
+                    },
+                    None => (format_descriptors().find(has_marker).map_or("unknown", |descriptor| descriptor.short_name), instantiate),
+                };
+
+                let format = instantiate(mss, &FormatOptions::default())?;
+                return Ok(ProbedFormat { format, format_name, metadata });
+            }
+            Instantiate::Metadata(instantiate) => {
+                metadata.push(instantiate(&MetadataOptions::default()).read_all(&mut mss)?);
+            }
+        }
+    }
+}
+
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Debug, Clone)]
+pub struct AudioTrack {
+    pub id: u32,
+    /// Short name of the codec, such as "mp3", "aac" or "pcm_s16le"
+    pub codec: String,
+    #[wasm_bindgen(js_name = "sampleRate")]
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u32>,
+    #[wasm_bindgen(js_name = "bitsPerSample")]
+    pub bits_per_sample: Option<u32>,
+    #[wasm_bindgen(js_name = "durationMs")]
+    pub duration_ms: Option<f64>,
+    pub language: Option<String>,
+}
+
+impl AudioTrack {
+    fn new(track: &Track) -> AudioTrack {
+        let codec_params = &track.codec_params;
+        AudioTrack {
+            id: track.id,
+            codec: symphonia::default::get_codecs()
+                .get_codec(codec_params.codec)
+                .map_or("unknown", |descriptor| descriptor.short_name)
+                .to_string(),
+            sample_rate: codec_params.sample_rate,
+            channels: codec_params.channels.map(|channels| channels.count() as u32),
+            bits_per_sample: codec_params.bits_per_sample.or(codec_params.bits_per_coded_sample),
+            duration_ms: duration_ms(codec_params),
+            language: track.language.clone(),
+        }
+    }
+}
+
+fn duration_ms(codec_params: &CodecParameters) -> Option<f64> {
+    let number_frames = codec_params.n_frames?;
+    match (codec_params.time_base, codec_params.sample_rate) {
+        (Some(time_base), _) if time_base.numer > 0 && time_base.denom > 0 => {
+            let time = time_base.calc_time(number_frames);
+            Some((time.seconds as f64 + time.frac) * 1000.0)
+        }
+        (_, Some(sample_rate)) if sample_rate > 0 => Some(number_frames as f64 * 1000.0 / sample_rate as f64),
+        _ => None,
+    }
+}
+
+/// What a song file contains, read from its headers without decoding any audio
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Debug, Clone)]
+pub struct AudioProbe {
+    /// Short name of the container format, such as "wave", "mp3" or "isomp4"
+    pub format: String,
+    /// The audio track fingerprinted by default, the first with a known codec
+    pub track: Option<AudioTrack>,
+    /// Every track of the container, in the order `TrackSelection::Index` refers to them
+    pub tracks: Vec<AudioTrack>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub isrc: Option<String>,
+}
+
+/// Reads the format, tracks and tags of a song file.
+pub fn probe_bytes(bytes: Vec<u8>, hint: &FormatHint) -> Result<AudioProbe, Error> {
+    let bytes = SharedBytes::from(bytes);
+    let open = || MediaSourceStream::new(Box::new(Cursor::new(bytes.clone())), Default::default());
+    let ProbedFormat { mut format, format_name, mut metadata } = probe_format(open, hint)?;
+
+    // Tags inside the container take precedence over ones found before it
+    if let Some(revision) = format.metadata().skip_to_latest() {
+        metadata.push(revision.clone());
+    }
+    let tag = |key: StandardTagKey| {
+        metadata
+            .iter()
+            .rev()
+            .flat_map(|revision| revision.tags())
+            .find(|tag| tag.std_key == Some(key))
+            // RIFF INFO strings keep their NUL terminator
+            .map(|tag| tag.value.to_string().trim_end_matches('\0').trim().to_string())
+    };
+
+    let tracks: Vec<AudioTrack> = format.tracks().iter().map(AudioTrack::new).collect();
+    let track = TrackSelection::default().select(format.as_ref()).ok().map(AudioTrack::new);
+    Ok(AudioProbe {
+        format: format_name.to_string(),
+        track,
+        tracks,
+        title: tag(StandardTagKey::TrackTitle),
+        artist: tag(StandardTagKey::Artist),
+        album: tag(StandardTagKey::Album),
+        isrc: tag(StandardTagKey::IdentIsrc),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprinting::test_audio::wav;
+
+    fn format_name(bytes: Vec<u8>, hint: &str) -> Result<&'static str, Error> {
+        let bytes = SharedBytes::from(bytes);
+        let open = || MediaSourceStream::new(Box::new(Cursor::new(bytes.clone())), Default::default());
+        probe_format(open, &FormatHint::parse(hint)).map(|probed| probed.format_name)
+    }
+
+    /// A single ADTS frame of 11 bytes, shorter than the probe's 16 byte window
+    const ADTS_FRAME: [u8; 11] = [0xff, 0xf1, 0x60, 0x40, 0x01, 0x7f, 0xfc, 0x00, 0x00, 0x00, 0x00];
+
+    #[test]
+    fn tries_the_hinted_format_before_markers_further_on() {
+        // Headerless data with a stray RIFF marker before the first ADTS sync word
+        let mut bytes = vec![0; 4];
+        bytes.extend(b"RIFF");
+        bytes.resize(bytes.len() + 16, 0);
+        bytes.extend(ADTS_FRAME);
+        bytes.extend(ADTS_FRAME);
+        assert!(format_name(bytes.clone(), "").is_err());
+        assert_eq!(format_name(bytes.clone(), "song.aac").unwrap(), "aac");
+        assert_eq!(format_name(bytes, "audio/aac").unwrap(), "aac");
+    }
+
+    #[test]
+    fn ignores_hints_contradicting_the_marker_at_the_start() {
+        let bytes = wav(16000, 1, &[0; 64]);
+        assert_eq!(format_name(bytes.clone(), "mp3").unwrap(), "wave");
+        assert_eq!(format_name(bytes, "wav").unwrap(), "wave");
+    }
+
+    #[test]
+    fn falls_back_to_the_marker_found_when_the_hinted_format_fails() {
+        let mut bytes = vec![0; 100];
+        bytes.extend(wav(16000, 1, &[0; 64]));
+        assert_eq!(format_name(bytes.clone(), "flac").unwrap(), "wave");
+        assert_eq!(format_name(bytes, "").unwrap(), "wave");
+    }
+
+    #[test]
+    fn probes_streams_ending_shortly_after_their_marker() {
+        assert_eq!(format_name(ADTS_FRAME.to_vec(), "aac").unwrap(), "aac");
+        // Metadata with nothing after it
+        assert!(format_name(b"ID3\x04\x00\x00\x00\x00\x00\x00".to_vec(), "").is_err());
+    }
+
+    #[test]
+    fn reads_hints_from_names_and_mime_types() {
+        assert_eq!(FormatHint::parse("Song.M4A").extension.as_deref(), Some("m4a"));
+        assert_eq!(FormatHint::parse(".flac").extension.as_deref(), Some("flac"));
+        assert_eq!(FormatHint::parse("audio/mpeg; codecs=mp3").mime_type.as_deref(), Some("audio/mpeg"));
+        assert_eq!(FormatHint::parse("aac").descriptor().map(|descriptor| descriptor.short_name), Some("aac"));
+        assert!(FormatHint::parse("txt").descriptor().is_none());
+    }
+}
@@ -0,0 +1,90 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use symphonia::core::errors::Error;
+
+/// Part of the work a `Progress` is about
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ProgressStage {
+    #[default]
+    Decoding,
+    Resampling,
+    Fingerprinting,
+}
+
+impl ProgressStage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProgressStage::Decoding => "decoding",
+            ProgressStage::Resampling => "resampling",
+            ProgressStage::Fingerprinting => "fingerprinting",
+        }
+    }
+}
+
+/// How far a decode got, reported after every packet, then while resampling and fingerprinting
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Progress {
+    pub stage: ProgressStage,
+    pub bytes_read: u64,
+    pub total_bytes: u64,
+    pub packets_decoded: u64,
+    /// Percentage of the audio decoded, when the duration of the track is known, or of the
+    /// audio resampled or fingerprinted
+    pub percent: Option<f32>,
+}
+
+/// Flag checked between packets and FFT passes, so a long decode can be stopped early
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Error returned by work stopped through this token
+    pub fn error() -> Error {
+        Error::IoError(io::Error::new(io::ErrorKind::Interrupted, "Decoding was cancelled"))
+    }
+}
+
+pub type ProgressCallback<'a> = Box<dyn FnMut(&Progress) + 'a>;
+
+/// Progress callback and cancellation token of a decode
+#[derive(Default)]
+pub struct DecodeObserver<'a> {
+    pub on_progress: Option<ProgressCallback<'a>>,
+    pub cancellation: CancellationToken,
+    /// Last progress reported, whose decode counters later stages carry on
+    last: Progress,
+}
+
+impl<'a> DecodeObserver<'a> {
+    pub fn new(on_progress: Option<ProgressCallback<'a>>, cancellation: CancellationToken) -> DecodeObserver<'a> {
+        DecodeObserver { on_progress, cancellation, last: Progress::default() }
+    }
+
+    pub(crate) fn report(&mut self, progress: &Progress) {
+        self.last = *progress;
+        if let Some(on_progress) = self.on_progress.as_mut() {
+            on_progress(progress);
+        }
+    }
+
+    /// Reports the percentage done of a stage after decoding, returning false once cancelled
+    /// so it can be handed to the resampling and FFT loops.
+    pub(crate) fn report_stage(&mut self, stage: ProgressStage, percent: f32) -> bool {
+        self.report(&Progress { stage, percent: Some(percent), ..self.last });
+        !self.cancellation.is_cancelled()
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+
+use wasm_bindgen::prelude::*;
+
+use crate::fingerprinting::gain::rms_db;
+use crate::fingerprinting::signature_generator::{FrequencyBand, FrequencyPeak, SAMPLES_PER_FFT_PASS};
+
+/// Peaks per second, across all bands, at which density scores full marks. Music
+/// usually yields 40 to 60, speech and quiet captures far fewer
+const REFERENCE_PEAKS_PER_SECOND: f32 = 40.0;
+
+/// Blocks of samples quieter than this RMS level count as silent
+const SILENT_BLOCK_DB: f32 = -60.0;
+
+/// Clipping this share of the samples or more scores nothing
+const MAX_CLIPPED_FRACTION: f32 = 0.05;
+
+/// Level and clipping of the samples a signature was generated from, measured before they are freed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PcmStats {
+    /// RMS level in dBFS
+    pub rms_db: f32,
+    /// Share of samples at full scale
+    pub clipped_fraction: f32,
+    /// Share of 100 ms blocks quieter than -60 dBFS
+    pub silent_fraction: f32,
+}
+
+impl PcmStats {
+    pub fn measure(samples: &[i16], sample_rate_hz: u32) -> Option<PcmStats> {
+        if samples.is_empty() {
+            return None;
+        }
+        let clipped_samples = samples.iter().filter(|sample| **sample == i16::MAX || **sample == i16::MIN).count();
+        let blocks: Vec<f32> = samples.chunks((sample_rate_hz as usize / 10).max(1)).map(rms_db).collect();
+        let silent_blocks = blocks.iter().filter(|block_db| **block_db < SILENT_BLOCK_DB).count();
+        Some(PcmStats {
+            rms_db: rms_db(samples),
+            clipped_fraction: clipped_samples as f32 / samples.len() as f32,
+            silent_fraction: silent_blocks as f32 / blocks.len() as f32,
+        })
+    }
+}
+
+/// How well a signature is likely to be recognized, to decide whether a capture is worth
+/// sending or should be recorded for longer
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Debug, Clone)]
+pub struct SignatureQuality {
+    /// Density, coverage, silence and clipping combined, between 0 and 1
+    pub score: f32,
+    /// Peaks per second across all frequency bands
+    #[wasm_bindgen(js_name = "peaksPerSecond")]
+    pub peaks_per_second: f32,
+    /// Peaks per second of each frequency band, from the lowest
+    #[wasm_bindgen(js_name = "bandPeaksPerSecond")]
+    pub band_peaks_per_second: Vec<f32>,
+    /// Share of every second of every frequency band holding at least one peak, between 0 and 1
+    #[wasm_bindgen(js_name = "bandCoverage")]
+    pub band_coverage: f32,
+    /// Median peak magnitude in dB of the FFT magnitude
+    #[wasm_bindgen(js_name = "medianMagnitudeDb")]
+    pub median_magnitude_db: f32,
+    /// Spread between the first and third quartile of peak magnitudes in dB
+    #[wasm_bindgen(js_name = "magnitudeSpreadDb")]
+    pub magnitude_spread_db: f32,
+    /// Share of samples clipped at full scale, unknown for signatures parsed from a uri or bytes
+    #[wasm_bindgen(js_name = "clippedFraction")]
+    pub clipped_fraction: Option<f32>,
+    /// Share of 100 ms blocks quieter than -60 dBFS, unknown for signatures parsed from a uri or bytes
+    #[wasm_bindgen(js_name = "silentFraction")]
+    pub silent_fraction: Option<f32>,
+}
+
+impl SignatureQuality {
+    /// Scores the peaks of a signature of `number_samples` at `sample_rate_hz`, along with the
+    /// statistics of its samples when they were available.
+    pub fn measure(
+        frequency_band_to_sound_peaks: &HashMap<FrequencyBand, Vec<FrequencyPeak>>,
+        number_samples: usize,
+        sample_rate_hz: u32,
+        pcm_stats: Option<&PcmStats>,
+    ) -> SignatureQuality {
+        let seconds = number_samples as f32 / sample_rate_hz as f32;
+        let slots = seconds.ceil().max(1.0) as usize;
+        let per_second = |peaks: usize| if seconds > 0.0 { peaks as f32 / seconds } else { 0.0 };
+
+        let mut band_peaks_per_second = Vec::with_capacity(FrequencyBand::ALL.len());
+        let mut covered_slots = 0;
+        let mut magnitudes_db = Vec::new();
+        for frequency_band in FrequencyBand::ALL {
+            let frequency_peaks = frequency_band_to_sound_peaks.get(&frequency_band).map_or(&[][..], Vec::as_slice);
+            band_peaks_per_second.push(per_second(frequency_peaks.len()));
+
+            let mut slot_has_peak = vec![false; slots];
+            for frequency_peak in frequency_peaks {
+                let slot = frequency_peak.fft_pass_number as usize * SAMPLES_PER_FFT_PASS / sample_rate_hz as usize;
+                slot_has_peak[slot.min(slots - 1)] = true;
+                magnitudes_db.push(magnitude_db(frequency_peak.peak_magnitude));
+            }
+            covered_slots += slot_has_peak.iter().filter(|has_peak| **has_peak).count();
+        }
+
+        let peaks_per_second: f32 = band_peaks_per_second.iter().sum();
+        let band_coverage = covered_slots as f32 / (slots * FrequencyBand::ALL.len()) as f32;
+        magnitudes_db.sort_by(f32::total_cmp);
+        let quantile = |q: f32| magnitudes_db.get(((magnitudes_db.len().max(1) - 1) as f32 * q).round() as usize).copied().unwrap_or(0.0);
+
+        let density = (peaks_per_second / REFERENCE_PEAKS_PER_SECOND).min(1.0);
+        let (clipped_fraction, silent_fraction) = (pcm_stats.map(|stats| stats.clipped_fraction), pcm_stats.map(|stats| stats.silent_fraction));
+        let clipping = (clipped_fraction.unwrap_or(0.0) / MAX_CLIPPED_FRACTION).min(1.0);
+        let score = (density + band_coverage) / 2.0 * (1.0 - silent_fraction.unwrap_or(0.0)) * (1.0 - clipping);
+
+        SignatureQuality {
+            score,
+            peaks_per_second,
+            band_peaks_per_second,
+            band_coverage,
+            median_magnitude_db: quantile(0.5),
+            magnitude_spread_db: quantile(0.75) - quantile(0.25),
+            clipped_fraction,
+            silent_fraction,
+        }
+    }
+}
+
+/// Peak magnitudes are stored as `ln(power) * 1477.3 + 6144`, the FFT output being squared magnitudes
+fn magnitude_db(peak_magnitude: u16) -> f32 {
+    (peak_magnitude as f32 - 6144.0) / 1477.3 * 10.0 / std::f32::consts::LN_10
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprinting::test_audio::{sine, to_i16};
+
+    const RATE: u32 = 16000;
+
+    fn assert_near(actual: f32, expected: f32, tolerance: f32) {
+        assert!((actual - expected).abs() < tolerance, "{} is not {}", actual, expected);
+    }
+
+    /// `peaks_per_second` peaks spread over the first `seconds` of a band, of the given magnitudes in turn
+    fn band_peaks(seconds: usize, peaks_per_second: usize, magnitudes: &[u16]) -> Vec<FrequencyPeak> {
+        let passes_per_second = RATE as usize / SAMPLES_PER_FFT_PASS;
+        (0..seconds * peaks_per_second)
+            .map(|index| FrequencyPeak {
+                fft_pass_number: (index * passes_per_second / peaks_per_second) as u32,
+                peak_magnitude: magnitudes[index % magnitudes.len()],
+                corrected_peak_frequency_bin: 5000,
+                sample_rate_hz: RATE,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn measures_level_silence_and_clipping() {
+        // A second of silence, then a second of a half scale sine
+        let mut samples = vec![0; RATE as usize];
+        samples.extend(to_i16(&sine(1000.0, RATE, RATE as usize, 0.5)));
+        let stats = PcmStats::measure(&samples, RATE).unwrap();
+        assert_near(stats.rms_db, -12.04, 0.01);
+        assert_eq!(stats.silent_fraction, 0.5);
+        assert_eq!(stats.clipped_fraction, 0.0);
+
+        let stats = PcmStats::measure(&[i16::MAX, i16::MIN, 0, 1000], RATE).unwrap();
+        assert_eq!(stats.clipped_fraction, 0.5);
+        assert!(PcmStats::measure(&[], RATE).is_none());
+    }
+
+    #[test]
+    fn converts_stored_magnitudes_to_db() {
+        assert_eq!(magnitude_db(6144), 0.0);
+        // A tenfold power is 10 dB, ln(10) * 1477.3 further up
+        assert_near(magnitude_db(9546), 10.0, 0.01);
+        assert_near(magnitude_db(2742), -10.0, 0.01);
+    }
+
+    #[test]
+    fn scores_dense_peaks_covering_every_band_full_marks() {
+        let magnitudes = [2742, 6144, 9546];
+        let peaks: HashMap<FrequencyBand, Vec<FrequencyPeak>> =
+            FrequencyBand::ALL.into_iter().map(|band| (band, band_peaks(10, 10, &magnitudes))).collect();
+        let quality = SignatureQuality::measure(&peaks, 10 * RATE as usize, RATE, None);
+
+        assert_eq!(quality.band_peaks_per_second, [10.0; 4]);
+        assert_eq!(quality.peaks_per_second, 40.0);
+        assert_eq!(quality.band_coverage, 1.0);
+        assert_near(quality.score, 1.0, 1e-6);
+        assert_eq!(quality.median_magnitude_db, 0.0);
+        assert_near(quality.magnitude_spread_db, 20.0, 0.01);
+        assert!(quality.clipped_fraction.is_none() && quality.silent_fraction.is_none());
+
+        // Silence and clipping the samples had take their share off
+        let stats = PcmStats { rms_db: -20.0, clipped_fraction: MAX_CLIPPED_FRACTION / 2.0, silent_fraction: 0.5 };
+        let quality = SignatureQuality::measure(&peaks, 10 * RATE as usize, RATE, Some(&stats));
+        assert_near(quality.score, 0.25, 1e-6);
+        assert_eq!(quality.silent_fraction, Some(0.5));
+    }
+
+    #[test]
+    fn scores_sparse_peaks_in_one_band_low() {
+        // 20 peaks a second in one band, for the first half of ten seconds
+        let peaks = HashMap::from([(FrequencyBand::_520_1450, band_peaks(5, 20, &[6144]))]);
+        let quality = SignatureQuality::measure(&peaks, 10 * RATE as usize, RATE, None);
+
+        assert_eq!(quality.band_peaks_per_second, [0.0, 10.0, 0.0, 0.0]);
+        assert_eq!(quality.band_coverage, 5.0 / 40.0);
+        // A quarter of the reference density and an eighth of the coverage
+        assert_near(quality.score, (0.25 + 0.125) / 2.0, 1e-6);
+
+        let empty = SignatureQuality::measure(&HashMap::new(), 0, RATE, None);
+        assert_eq!((empty.score, empty.peaks_per_second, empty.band_coverage), (0.0, 0.0, 0.0));
+    }
+}
+
@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+
+use wasm_bindgen::prelude::*;
+
+use crate::fingerprinting::decoded_signature::DEFAULT_SAMPLE_RATE;
+use crate::fingerprinting::landmarks::{landmarks_from_peaks, Landmark};
+use crate::fingerprinting::samples_from_bytes::pcm_from_bytes;
+use crate::fingerprinting::signature_generator::{SignatureGenerator, SAMPLES_PER_FFT_PASS};
+
+/// Hashes occurring more often than this are too common (steady tones, silence) to tell repeats apart
+const MAX_HASH_OCCURRENCES: usize = 32;
+
+/// Largest gap between matching landmarks of one repeat
+const MAX_GAP_MS: u32 = 2000;
+
+pub struct RepeatOptions {
+    /// Shortest repeated segment to report
+    pub min_duration_ms: u32,
+    /// Shortest time between two occurrences within the same recording
+    pub min_separation_ms: u32,
+    /// Minimum number of landmarks two occurrences must share
+    pub min_matched_landmarks: usize,
+}
+
+impl Default for RepeatOptions {
+    fn default() -> Self {
+        RepeatOptions {
+            min_duration_ms: 3000,
+            min_separation_ms: 10000,
+            min_matched_landmarks: 20,
+        }
+    }
+}
+
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy)]
+pub struct SegmentOccurrence {
+    /// Index of the recording, in the order they were added
+    pub recording: u32,
+    #[wasm_bindgen(js_name = "startMs")]
+    pub start_ms: u32,
+    #[wasm_bindgen(js_name = "endMs")]
+    pub end_ms: u32,
+}
+
+#[wasm_bindgen]
+#[derive(Debug, Clone)]
+pub struct RepeatedSegment {
+    occurrences: Vec<SegmentOccurrence>,
+    duration_ms: u32,
+}
+
+#[wasm_bindgen]
+impl RepeatedSegment {
+    /// Every place the segment occurs, ordered by recording and time
+    #[wasm_bindgen(getter)]
+    pub fn occurrences(&self) -> Vec<SegmentOccurrence> {
+        self.occurrences.clone()
+    }
+
+    /// Median duration of the occurrences
+    #[wasm_bindgen(getter, js_name = "durationMs")]
+    pub fn duration_ms(&self) -> u32 {
+        self.duration_ms
+    }
+}
+
+/// Finds content repeated within or across recordings, such as ads, station
+/// idents and jingles, without a reference catalogue
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct RepeatFinder {
+    recordings: Vec<Vec<Landmark>>,
+}
+
+#[wasm_bindgen]
+impl RepeatFinder {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> RepeatFinder {
+        RepeatFinder::default()
+    }
+
+    /// Fingerprints a recording and returns its index.
+    #[wasm_bindgen(js_name = "addBytes")]
+    pub fn add_bytes(&mut self, bytes: Vec<u8>) -> Result<u32, JsValue> {
+        let samples = pcm_from_bytes(bytes).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let frequency_band_to_sound_peaks = SignatureGenerator::frequency_band_to_sound_peaks(&samples);
+        self.recordings.push(landmarks_from_peaks(&frequency_band_to_sound_peaks));
+        Ok(self.recordings.len() as u32 - 1)
+    }
+
+    /// Finds the segments repeated within or across the recordings added, most repeated first.
+    pub fn find(&self, min_duration_ms: Option<u32>) -> Vec<RepeatedSegment> {
+        let defaults = RepeatOptions::default();
+        // `addBytes` fingerprints at the default rate
+        find_repeats(&self.recordings, DEFAULT_SAMPLE_RATE, &RepeatOptions {
+            min_duration_ms: min_duration_ms.unwrap_or(defaults.min_duration_ms),
+            ..defaults
+        })
+    }
+}
+
+fn overlaps(a: &SegmentOccurrence, b: &SegmentOccurrence) -> bool {
+    let overlap = a.end_ms.min(b.end_ms).saturating_sub(a.start_ms.max(b.start_ms));
+    let shorter = (a.end_ms - a.start_ms).min(b.end_ms - b.start_ms);
+    a.recording == b.recording && overlap * 2 >= shorter
+}
+
+fn find_root(parents: &mut [usize], mut index: usize) -> usize {
+    while parents[index] != index {
+        parents[index] = parents[parents[index]];
+        index = parents[index];
+    }
+    index
+}
+
+/// Self-matches the landmarks of recordings fingerprinted at `sample_rate_hz` to find segments
+/// that occur more than once.
+pub fn find_repeats(recordings: &[Vec<Landmark>], sample_rate_hz: u32, options: &RepeatOptions) -> Vec<RepeatedSegment> {
+    let to_passes = |ms: u32| (ms as u64 * sample_rate_hz as u64 / SAMPLES_PER_FFT_PASS as u64 / 1000) as u32;
+    let to_ms = |passes: u32| (passes as u64 * SAMPLES_PER_FFT_PASS as u64 * 1000 / sample_rate_hz as u64) as u32;
+
+    let mut occurrences_by_hash: HashMap<u32, Vec<(u32, u32)>> = HashMap::new();
+    for (recording, landmarks) in recordings.iter().enumerate() {
+        for landmark in landmarks {
+            occurrences_by_hash
+                .entry(landmark.hash)
+                .or_default()
+                .push((recording as u32, landmark.fft_pass_number));
+        }
+    }
+
+    // Every pair of occurrences of a hash votes for (first recording, second recording, time difference),
+    // remembering where in the first recording the vote came from
+    let min_separation = to_passes(options.min_separation_ms) as i64;
+    let mut votes: HashMap<(u32, u32, i64), Vec<u32>> = HashMap::new();
+    for occurrences in occurrences_by_hash.values_mut() {
+        if occurrences.len() < 2 || occurrences.len() > MAX_HASH_OCCURRENCES {
+            continue;
+        }
+        occurrences.sort_unstable();
+        for (index, first) in occurrences.iter().enumerate() {
+            for second in &occurrences[index + 1..] {
+                let delta = second.1 as i64 - first.1 as i64;
+                if first.0 == second.0 && delta < min_separation {
+                    continue;
+                }
+                votes.entry((first.0, second.0, delta)).or_default().push(first.1);
+            }
+        }
+    }
+
+    // Split the votes of each alignment, counting one FFT pass either side, into runs of nearby landmarks
+    let min_duration = to_passes(options.min_duration_ms);
+    let max_gap = to_passes(MAX_GAP_MS);
+    let mut pairs: Vec<(SegmentOccurrence, SegmentOccurrence)> = Vec::new();
+    for (first_recording, second_recording, delta) in votes.keys() {
+        let mut times: Vec<u32> = (delta - 1..=delta + 1)
+            .filter_map(|d| votes.get(&(*first_recording, *second_recording, d)))
+            .flatten()
+            .copied()
+            .collect();
+        if times.len() < options.min_matched_landmarks {
+            continue;
+        }
+        times.sort_unstable();
+
+        let mut run_start = 0;
+        for index in 1..=times.len() {
+            if index < times.len() && times[index] - times[index - 1] <= max_gap {
+                continue;
+            }
+            let (start, end) = (times[run_start], times[index - 1] + 1);
+            if index - run_start >= options.min_matched_landmarks && end - start >= min_duration {
+                let occurrence = |recording: u32, shift: i64| SegmentOccurrence {
+                    recording,
+                    start_ms: to_ms((start as i64 + shift) as u32),
+                    end_ms: to_ms((end as i64 + shift) as u32),
+                };
+                pairs.push((occurrence(*first_recording, 0), occurrence(*second_recording, *delta)));
+            }
+            run_start = index;
+        }
+    }
+
+    // Group occurrences that overlap into clusters of the same content
+    let occurrences: Vec<SegmentOccurrence> = pairs.iter().flat_map(|(a, b)| [*a, *b]).collect();
+    let mut parents: Vec<usize> = (0..occurrences.len()).collect();
+    for index in 0..pairs.len() {
+        let root = find_root(&mut parents, index * 2 + 1);
+        parents[root] = find_root(&mut parents, index * 2);
+    }
+    let mut by_start: Vec<usize> = (0..occurrences.len()).collect();
+    by_start.sort_by_key(|index| (occurrences[*index].recording, occurrences[*index].start_ms));
+    for window in 0..by_start.len() {
+        for other in &by_start[window + 1..] {
+            let (a, b) = (&occurrences[by_start[window]], &occurrences[*other]);
+            if b.recording != a.recording || b.start_ms >= a.end_ms {
+                break;
+            }
+            if overlaps(a, b) {
+                let root = find_root(&mut parents, *other);
+                parents[root] = find_root(&mut parents, by_start[window]);
+            }
+        }
+    }
+
+    let mut clusters: HashMap<usize, Vec<SegmentOccurrence>> = HashMap::new();
+    for index in by_start {
+        let root = find_root(&mut parents, index);
+        let cluster = clusters.entry(root).or_default();
+        // Occurrences are in order, so overlapping ones of the same place are merged with the last
+        match cluster.last_mut() {
+            Some(last) if overlaps(last, &occurrences[index]) => {
+                last.end_ms = last.end_ms.max(occurrences[index].end_ms);
+            }
+            _ => cluster.push(occurrences[index]),
+        }
+    }
+
+    let mut repeated_segments: Vec<RepeatedSegment> = clusters
+        .into_values()
+        .filter(|occurrences| occurrences.len() > 1)
+        .map(|occurrences| {
+            let mut durations: Vec<u32> = occurrences.iter().map(|o| o.end_ms - o.start_ms).collect();
+            durations.sort_unstable();
+            RepeatedSegment { duration_ms: durations[durations.len() / 2], occurrences }
+        })
+        .collect();
+    repeated_segments.sort_by(|x, y| {
+        y.occurrences
+            .len()
+            .cmp(&x.occurrences.len())
+            .then(y.duration_ms.cmp(&x.duration_ms))
+            .then(x.occurrences[0].start_ms.cmp(&y.occurrences[0].start_ms))
+    });
+    repeated_segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 100 landmarks 5 FFT passes apart, 4 seconds at 16 KHz, with hashes no other content shares
+    fn content(id: u32, start_pass: u32) -> Vec<Landmark> {
+        (0..100).map(|index| Landmark { hash: id << 16 | index, fft_pass_number: start_pass + index * 5 }).collect()
+    }
+
+    fn recording(parts: &[(u32, u32)]) -> Vec<Landmark> {
+        parts.iter().flat_map(|(id, start_pass)| content(*id, *start_pass)).collect()
+    }
+
+    fn places(segment: &RepeatedSegment) -> Vec<(u32, u32)> {
+        segment.occurrences.iter().map(|occurrence| (occurrence.recording, occurrence.start_ms)).collect()
+    }
+
+    #[test]
+    fn clusters_the_pairs_of_one_segment_into_its_occurrences() {
+        // Three occurrences match pairwise three times, and are joined into one segment
+        let recordings = [recording(&[(1, 1000), (2, 3000), (1, 5000)]), recording(&[(1, 2000)])];
+        let segments = find_repeats(&recordings, 16000, &RepeatOptions::default());
+        assert_eq!(segments.len(), 1);
+        assert_eq!(places(&segments[0]), [(0, 8000), (0, 40000), (1, 16000)]);
+        assert_eq!(segments[0].duration_ms, 3968);
+    }
+
+    #[test]
+    fn keeps_different_segments_apart_most_repeated_first() {
+        let recordings = [recording(&[(1, 1000), (2, 3000), (1, 5000), (2, 7000)]), recording(&[(1, 2000)])];
+        let segments = find_repeats(&recordings, 16000, &RepeatOptions::default());
+        let found: Vec<Vec<(u32, u32)>> = segments.iter().map(places).collect();
+        assert_eq!(found, [vec![(0, 8000), (0, 40000), (1, 16000)], vec![(0, 24000), (0, 56000)]]);
+    }
+
+    #[test]
+    fn merges_overlapping_occurrences_of_the_same_place() {
+        // Content repeated back to back, so the middle occurrence is found from either side
+        let recordings = [recording(&[(1, 1000), (1, 3000), (1, 5000)])];
+        let segments = find_repeats(&recordings, 16000, &RepeatOptions::default());
+        assert_eq!(segments.len(), 1);
+        assert_eq!(places(&segments[0]), [(0, 8000), (0, 24000), (0, 40000)]);
+    }
+
+    #[test]
+    fn times_occurrences_at_the_sample_rate_of_the_landmarks() {
+        let recordings = [recording(&[(1, 1000), (1, 5000)])];
+        let segments = find_repeats(&recordings, 8000, &RepeatOptions::default());
+        assert_eq!(places(&segments[0]), [(0, 16000), (0, 80000)]);
+        assert_eq!(segments[0].duration_ms, 7936);
+    }
+
+    #[test]
+    fn finds_roots_compressing_paths() {
+        let mut parents = vec![0, 0, 1, 2, 4];
+        assert_eq!(find_root(&mut parents, 3), 0);
+        assert_eq!(find_root(&mut parents, 4), 4);
+        assert!(parents[3] < 3);
+    }
+}
@@ -1,37 +1,230 @@
-use rubato::Resampler;
+use rubato::FftFixedIn;
 use rubato::SincFixedIn;
 use rubato::SincInterpolationType;
 use rubato::SincInterpolationParameters;
+use rubato::VecResampler;
 
-pub fn resample(sample_rate: u32, channel_count: usize, samples: &Vec<f32>, target_rate: i32) -> Vec<i16> {
-    let resample_ratio = target_rate as f64 / sample_rate as f64;
-    let max_resample_ratio_relative = 2.0;
-    let chunk_size = samples.len() / channel_count;
-    let parameters = SincInterpolationParameters {
-        sinc_len: 256,
-        f_cutoff: 0.95,
-        interpolation: SincInterpolationType::Cubic,
-        oversampling_factor: 160,
-        window: rubato::WindowFunction::BlackmanHarris2,
-    };
-    let mut resampler = SincFixedIn::new(
-        resample_ratio,
-        max_resample_ratio_relative,
-        parameters,
-        chunk_size,
-        1,
-    ).expect("Failed to create resampler");
+/// Frames resampled at a time, so the resampler's buffers don't grow with the input
+const CHUNK_FRAMES: usize = 8192;
+
+/// Chunks between two progress reports, a few seconds of audio at 44.1 KHz
+const CHUNKS_PER_PROGRESS: usize = 16;
+
+/// Trade-off between resampling speed and accuracy
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ResampleQuality {
+    /// FFT based, by far the fastest
+    Fast,
+    /// 64-tap sinc with linear interpolation
+    Balanced,
+    /// 256-tap sinc with cubic interpolation
+    #[default]
+    Best,
+}
+
+impl ResampleQuality {
+    /// Parses `"fast"`, `"balanced"` or `"best"`.
+    pub fn parse(value: &str) -> Option<ResampleQuality> {
+        match value.to_ascii_lowercase().as_str() {
+            "fast" => Some(ResampleQuality::Fast),
+            "balanced" => Some(ResampleQuality::Balanced),
+            "best" => Some(ResampleQuality::Best),
+            _ => None,
+        }
+    }
 
+    fn resampler(&self, sample_rate: u32, target_rate: u32) -> Box<dyn VecResampler<f32>> {
+        let resample_ratio = target_rate as f64 / sample_rate as f64;
+        let max_resample_ratio_relative = 2.0;
+        let parameters = match self {
+            ResampleQuality::Fast => {
+                return Box::new(
+                    FftFixedIn::new(sample_rate as usize, target_rate as usize, CHUNK_FRAMES, 2, 1).expect("Failed to create resampler"),
+                )
+            }
+            ResampleQuality::Balanced => SincInterpolationParameters {
+                sinc_len: 64,
+                f_cutoff: 0.91,
+                interpolation: SincInterpolationType::Linear,
+                oversampling_factor: 128,
+                window: rubato::WindowFunction::Blackman2,
+            },
+            ResampleQuality::Best => SincInterpolationParameters {
+                sinc_len: 256,
+                f_cutoff: 0.95,
+                interpolation: SincInterpolationType::Cubic,
+                oversampling_factor: 160,
+                window: rubato::WindowFunction::BlackmanHarris2,
+            },
+        };
+        Box::new(
+            SincFixedIn::new(resample_ratio, max_resample_ratio_relative, parameters, CHUNK_FRAMES, 1)
+                .expect("Failed to create resampler"),
+        )
+    }
+}
+
+/// Averages interleaved samples into a single channel.
+pub fn downmix(samples: &[f32], channel_count: usize) -> Vec<f32> {
     let mut mono_samples = vec![0f32; samples.len() / channel_count];
     for (i, sample) in samples.iter().enumerate() {
-        mono_samples[i / channel_count] += sample / channel_count as f32;
+        if let Some(mono_sample) = mono_samples.get_mut(i / channel_count) {
+            *mono_sample += sample / channel_count as f32;
+        }
+    }
+    mono_samples
+}
+
+/// Downmixes interleaved samples and resamples them to `target_rate`, a chunk at a time.
+/// Samples already at `target_rate` are only downmixed.
+pub fn resample_mono(sample_rate: u32, channel_count: usize, samples: &[f32], target_rate: u32, quality: ResampleQuality) -> Vec<f32> {
+    resample_mono_observed(sample_rate, channel_count, samples, target_rate, quality, |_| true).unwrap_or_default()
+}
+
+/// Like `resample_mono`, calling `on_progress` with the share of the samples done every few
+/// chunks and stopping with `None` once it returns false.
+pub fn resample_mono_observed(
+    sample_rate: u32,
+    channel_count: usize,
+    samples: &[f32],
+    target_rate: u32,
+    quality: ResampleQuality,
+    mut on_progress: impl FnMut(f32) -> bool,
+) -> Option<Vec<f32>> {
+    if sample_rate == target_rate {
+        return Some(downmix(samples, channel_count));
+    }
+    let number_frames = samples.len() / channel_count;
+    if number_frames == 0 {
+        return Some(Vec::new());
     }
 
-    let resampled_samples = resampler.process(&[&mono_samples], None).expect("Failed to resample");
-    let result: Vec<i16> = resampled_samples[0]
+    let mut resampler = quality.resampler(sample_rate, target_rate);
+    // The FFT resampler's output lags its input, the lag is trimmed so the output lines up.
+    // Rubato's sinc resamplers already center their output, despite the delay they report.
+    let delay = match quality {
+        ResampleQuality::Fast => resampler.output_delay(),
+        ResampleQuality::Balanced | ResampleQuality::Best => 0,
+    };
+    let expected_frames = (number_frames as f64 * target_rate as f64 / sample_rate as f64).round() as usize;
+    let mut resampled_samples = Vec::with_capacity(delay + expected_frames + resampler.output_frames_max());
+    let mut output_buffer = resampler.output_buffer_allocate(true);
+
+    for (index, chunk) in samples.chunks(CHUNK_FRAMES * channel_count).enumerate() {
+        if index % CHUNKS_PER_PROGRESS == 0 && !on_progress((index * CHUNK_FRAMES) as f32 / number_frames as f32) {
+            return None;
+        }
+        let input = [downmix(chunk, channel_count)];
+        let (_, output_frames) = if input[0].len() == CHUNK_FRAMES {
+            resampler.process_into_buffer(&input, &mut output_buffer, None)
+        } else {
+            resampler.process_partial_into_buffer(Some(&input), &mut output_buffer, None)
+        }
+        .expect("Failed to resample");
+        resampled_samples.extend_from_slice(&output_buffer[0][..output_frames]);
+    }
+    // Push out what the resampler still holds back
+    while resampled_samples.len() < delay + expected_frames {
+        let (_, output_frames) = resampler.process_partial_into_buffer(None, &mut output_buffer, None).expect("Failed to resample");
+        if output_frames == 0 {
+            break;
+        }
+        resampled_samples.extend_from_slice(&output_buffer[0][..output_frames]);
+    }
+
+    resampled_samples.drain(..delay.min(resampled_samples.len()));
+    resampled_samples.truncate(expected_frames);
+    Some(resampled_samples)
+}
+
+pub fn resample_with_quality(sample_rate: u32, channel_count: usize, samples: &[f32], target_rate: i32, quality: ResampleQuality) -> Vec<i16> {
+    let result: Vec<i16> = resample_mono(sample_rate, channel_count, samples, target_rate as u32, quality)
         .iter()
         .map(|&sample| (sample * i16::MAX as f32) as i16)
         .collect();
 
     result
-}
\ No newline at end of file
+}
+
+pub fn resample(sample_rate: u32, channel_count: usize, samples: &[f32], target_rate: i32) -> Vec<i16> {
+    resample_with_quality(sample_rate, channel_count, samples, target_rate, ResampleQuality::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprinting::test_audio::sine;
+
+    const QUALITIES: [ResampleQuality; 3] = [ResampleQuality::Fast, ResampleQuality::Balanced, ResampleQuality::Best];
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn only_downmixes_samples_at_the_target_rate() {
+        let stereo = [0.5, 0.25, -1.0, 1.0, 0.2, 0.0];
+        assert_eq!(resample_mono(16000, 2, &stereo, 16000, ResampleQuality::Best), [0.375, 0.0, 0.1]);
+        assert_eq!(resample(16000, 1, &[0.5, -0.25], 16000), [16383, -8191]);
+    }
+
+    /// Amplitude of the `frequency` Hz component of one second of samples
+    fn amplitude_at(samples: &[f32], frequency: f32) -> f32 {
+        let (mut real, mut imaginary) = (0f32, 0f32);
+        for (i, sample) in samples.iter().enumerate() {
+            let phase = 2.0 * std::f32::consts::PI * frequency * i as f32 / samples.len() as f32;
+            real += sample * phase.cos();
+            imaginary += sample * phase.sin();
+        }
+        2.0 * real.hypot(imaginary) / samples.len() as f32
+    }
+
+    #[test]
+    fn keeps_tones_below_the_target_nyquist_frequency() {
+        let input = sine(1000.0, 44100, 44100, 0.5);
+        for quality in QUALITIES {
+            let output = resample_mono(44100, 1, &input, 16000, quality);
+            assert_eq!(output.len(), 16000, "{:?}", quality);
+            // All the energy is left in the tone, at its level
+            assert!((amplitude_at(&output, 1000.0) - 0.5).abs() < 0.005, "{:?}", quality);
+            assert!((rms(&output) - 0.5 / std::f32::consts::SQRT_2).abs() < 0.005, "{:?}", quality);
+        }
+    }
+
+    #[test]
+    fn filters_out_tones_above_the_target_nyquist_frequency() {
+        let input = sine(10000.0, 44100, 44100, 0.5);
+        for quality in QUALITIES {
+            let output = resample_mono(44100, 1, &input, 16000, quality);
+            assert!(rms(&output[1000..15000]) < 0.005, "{:?} lets {} through", quality, rms(&output[1000..15000]));
+        }
+    }
+
+    #[test]
+    fn resamples_chunk_after_chunk_and_stops_when_asked() {
+        // Several chunks of stereo input, downmixed before resampling
+        let mono = sine(440.0, 48000, 48000 * 3, 0.5);
+        let stereo: Vec<f32> = mono.iter().flat_map(|sample| [*sample, *sample]).collect();
+        let output = resample_mono(48000, 2, &stereo, 16000, ResampleQuality::Balanced);
+        assert_eq!(output.len(), 48000);
+        assert!((rms(&output[1000..47000]) - 0.5 / std::f32::consts::SQRT_2).abs() < 0.01);
+
+        let mut reports = Vec::new();
+        let stopped = resample_mono_observed(48000, 2, &stereo, 16000, ResampleQuality::Fast, |progress| {
+            reports.push(progress);
+            reports.len() < 2
+        });
+        assert!(stopped.is_none());
+        assert_eq!(reports.len(), 2);
+        assert!(reports[0] == 0.0 && reports[1] > 0.0 && reports[1] < 1.0);
+    }
+
+    #[test]
+    fn parses_presets() {
+        assert_eq!(ResampleQuality::parse("FAST"), Some(ResampleQuality::Fast));
+        assert_eq!(ResampleQuality::parse("balanced"), Some(ResampleQuality::Balanced));
+        assert_eq!(ResampleQuality::parse("best"), Some(ResampleQuality::Best));
+        assert_eq!(ResampleQuality::parse("linear"), None);
+    }
+}
+
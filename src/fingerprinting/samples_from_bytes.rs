@@ -1,77 +1,367 @@
-use std::io::Cursor;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
-use symphonia::core::io::MediaSourceStream;
+use crate::fingerprinting::decoded_signature::SilenceOptions;
+use crate::fingerprinting::downmix::Downmix;
+use crate::fingerprinting::gain::Normalization;
+use crate::fingerprinting::preprocess::FilterChain;
+use crate::fingerprinting::probe::{probe_format, FormatHint, ProbedFormat, SharedBytes, TrackSelection};
+use crate::fingerprinting::progress::{CancellationToken, DecodeObserver, Progress, ProgressStage};
+use crate::fingerprinting::resample::{downmix, resample, resample_mono, ResampleQuality};
+
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use wasm_bindgen::prelude::*;
 use symphonia::core::audio::{Channels, SampleBuffer, SignalSpec};
 use symphonia::core::errors::Error;
-use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
-use symphonia::core::formats::FormatOptions;
-use symphonia::core::meta::MetadataOptions;
-use symphonia::core::probe::Hint;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::units::TimeBase;
+
+/// Media source over the song bytes, sharing how far they have been read
+struct ProgressSource {
+    cursor: Cursor<SharedBytes>,
+    bytes_read: Arc<AtomicU64>,
+}
+
+impl Read for ProgressSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = self.cursor.read(buf)?;
+        self.bytes_read.store(self.cursor.position(), Ordering::Relaxed);
+        Ok(length)
+    }
+}
+
+impl Seek for ProgressSource {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = self.cursor.seek(pos)?;
+        self.bytes_read.store(position, Ordering::Relaxed);
+        Ok(position)
+    }
+}
+
+impl MediaSource for ProgressSource {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        Some(self.cursor.get_ref().as_ref().len() as u64)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DecodeOptions {
+    /// Audio track to decode
+    pub track: TrackSelection,
+    /// Container format suggested by the file name or MIME type
+    pub hint: FormatHint,
+    /// Skip packets that fail to decode, replacing them with silence to keep timing,
+    /// and keep what was decoded when the container cannot be read any further
+    pub tolerant: bool,
+    /// How the decoded channels are mixed into the fingerprinted mono signal
+    pub downmix: Downmix,
+    /// Rate signatures are generated at, one of `SAMPLE_RATES`, 16 KHz when unset
+    pub sample_rate: Option<u32>,
+    /// Trade-off between speed and accuracy when resampling to the signature rate
+    pub resample_quality: ResampleQuality,
+    /// Filters applied to the resampled signal before normalization, see `PreprocessOptions`
+    pub filters: FilterChain,
+    /// How the level of the resampled signal is adjusted before it is quantized to 16 bits
+    pub normalization: Normalization,
+    /// Add TPDF dither when quantizing to 16 bits
+    pub dither: bool,
+    /// When signatures count as silent, and whether they are dropped
+    pub silence: SilenceOptions,
+}
+
+/// A packet replaced with silence by tolerant decoding
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Debug, Clone)]
+pub struct SkippedPacket {
+    #[wasm_bindgen(js_name = "timestampMs")]
+    pub timestamp_ms: f64,
+    #[wasm_bindgen(js_name = "durationMs")]
+    pub duration_ms: f64,
+    pub error: String,
+}
+
+/// What was found while decoding
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Debug, Clone, Default)]
+pub struct DecodeReport {
+    /// Short name of the container format detected, such as "wave", "mp3" or "isomp4"
+    pub format: String,
+    /// Packets that failed to decode, only skipped when decoding tolerantly
+    #[wasm_bindgen(js_name = "skippedPackets")]
+    pub skipped_packets: Vec<SkippedPacket>,
+    /// The stream ended before the number of frames its header announced
+    pub truncated: bool,
+    /// Error that stopped reading the container early, only kept when decoding tolerantly
+    pub error: Option<String>,
+    /// Gain applied by normalization in dB, averaged over the signal for the AGC
+    #[wasm_bindgen(js_name = "gainDb")]
+    pub gain_db: f32,
+    /// Samples beyond full scale that were clipped when quantizing to 16 bits
+    #[wasm_bindgen(js_name = "clippedSamples")]
+    pub clipped_samples: u32,
+    /// Signatures judged silent or too poor in peaks, whether they were dropped or not
+    #[wasm_bindgen(js_name = "silentSegments")]
+    pub silent_segments: u32,
+}
 
 pub fn samples_from_bytes(
     bytes: Vec<u8>,
     sample_seconds: usize
 ) -> Result<(SignalSpec, Vec<f32>), Error> {
-    // Create the media source stream.
-    let mss = MediaSourceStream::new(Box::new(Cursor::new(bytes)), Default::default());    
+    let (spec, samples, _) = samples_from_bytes_observed(bytes, sample_seconds, &DecodeOptions::default(), &mut DecodeObserver::default())?;
+    Ok((spec, samples))
+}
 
-    let probe_result = symphonia::default::get_probe().format(&Hint::new(), mss, &FormatOptions::default(), &MetadataOptions::default())?;
+/// Like `samples_from_bytes` with decode options, reporting progress after every packet
+/// and stopping with an `Interrupted` IO error once the observer's token is cancelled.
+pub fn samples_from_bytes_observed(
+    bytes: Vec<u8>,
+    sample_seconds: usize,
+    options: &DecodeOptions,
+    observer: &mut DecodeObserver,
+) -> Result<(SignalSpec, Vec<f32>, DecodeReport), Error> {
+    let total_bytes = bytes.len() as u64;
+    let shared_bytes = SharedBytes::from(bytes);
+
+    // Create the media source stream.
+    let bytes_read = Arc::new(AtomicU64::new(0));
+    let open = || {
+        let source = ProgressSource { cursor: Cursor::new(shared_bytes.clone()), bytes_read: bytes_read.clone() };
+        MediaSourceStream::new(Box::new(source), Default::default())
+    };
 
     // Get the instantiated format reader.
-    let mut format = probe_result.format;
+    let ProbedFormat { mut format, format_name, .. } = probe_format(open, &options.hint)?;
+    let mut report = DecodeReport { format: format_name.to_string(), ..Default::default() };
 
-    // Find the first audio track with a known (decodeable) codec.
-    let track = format
-        .tracks()
-        .iter()
-        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
-        .ok_or(Error::Unsupported("codec"))?;
+    // Find the selected audio track, by default the first with a known (decodeable) codec.
+    let track = options.track.select(format.as_ref())?;
 
     // Create a decoder for the track.
     let mut decoder = symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
 
     // Store the track identifier, it will be used to filter packets.
-    let track_id = track.id;
+    let mut track_id = track.id;
+    let mut number_frames = track.codec_params.n_frames;
+    let mut time_base = track.codec_params.time_base;
+    let mut packets_decoded = 0;
+    let mut end_ts = 0;
 
-    let mut spec: SignalSpec = SignalSpec::new(track.codec_params.sample_rate.unwrap_or(48000), Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
-    let mut aggregate_samples: Vec<f32> = Vec::with_capacity(12 * spec.rate as usize * 2);
+    // The container's channel layout, until the decoder reports the one it decodes
+    let channels = track.codec_params.channels
+        .or_else(|| track.codec_params.channel_layout.map(|layout| layout.into_channels()))
+        .unwrap_or(Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+    let mut spec: SignalSpec = SignalSpec::new(track.codec_params.sample_rate.unwrap_or(48000), channels);
+    let mut aggregate = Aggregate::new(spec);
     let mut sample_buf = SampleBuffer::<f32>::new(0, spec);
+    let mut silence: Vec<f32> = Vec::new();
     loop {
         // Get the next packet from the format reader.
         let packet = match format.next_packet() {
             Ok(packet) => packet,
-            Err(_) => break,
+            // Symphonia reports the end of the stream as an unexpected end of file
+            Err(Error::IoError(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                report.truncated = number_frames.is_some_and(|number_frames| end_ts < number_frames);
+                break;
+            }
+            // A new track chain starts, such as chained Ogg streams, which needs a new decoder
+            Err(Error::ResetRequired) => {
+                let track = options.track.select(format.as_ref())?;
+                decoder = symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+                track_id = track.id;
+                number_frames = track.codec_params.n_frames;
+                time_base = track.codec_params.time_base;
+                end_ts = 0;
+                continue;
+            }
+            Err(err) if options.tolerant => {
+                report.error = Some(err.to_string());
+                break;
+            }
+            Err(err) => return Err(err),
         };
+        if observer.cancellation.is_cancelled() {
+            return Err(CancellationToken::error());
+        }
         // If the packet does not belong to the selected track, skip it.
         if packet.track_id() != track_id {
             continue;
         }
+        packets_decoded += 1;
+        end_ts = end_ts.max(packet.ts() + packet.dur());
+        observer.report(&Progress {
+            stage: ProgressStage::Decoding,
+            bytes_read: bytes_read.load(Ordering::Relaxed),
+            total_bytes,
+            packets_decoded,
+            percent: number_frames
+                .filter(|number_frames| *number_frames > 0)
+                .map(|number_frames| ((packet.ts() + packet.dur()) as f32 / number_frames as f32 * 100.0).min(100.0)),
+        });
 
-        match decoder.decode(&packet) {
+        let samples = match decoder.decode(&packet) {
             Ok(audio_buf) => {
                 spec = *audio_buf.spec();
                 let audi_buf_capacity = audio_buf.capacity();
                 
-                if sample_buf.capacity() < audi_buf_capacity {
+                // The sample buffer's capacity counts samples of every channel, the audio buffer's counts frames
+                if sample_buf.capacity() < audi_buf_capacity * spec.channels.count() {
                     sample_buf = SampleBuffer::<f32>::new(audi_buf_capacity as u64, spec);
                 }
                 sample_buf.copy_interleaved_ref(audio_buf);
-                
-                let samples = sample_buf.samples();
-                let current_samples = aggregate_samples.len();
-
-                let max_samples = sample_seconds * spec.rate as usize * spec.channels.count();
-                if current_samples + samples.len() > max_samples {
-                    // Calculate how many samples can still be added without exceeding the max size
-                    aggregate_samples.extend_from_slice(&samples[..max_samples - current_samples]);
-                    break;
-                }
-
-                aggregate_samples.extend_from_slice(samples);
+                sample_buf.samples()
+            }
+            // A corrupt packet, the decoder recovers on the next one
+            Err(Error::DecodeError(err)) if options.tolerant => {
+                let frames = duration_frames(packet.dur(), time_base, spec.rate);
+                report.skipped_packets.push(SkippedPacket {
+                    timestamp_ms: duration_ms(packet.ts(), time_base, spec.rate),
+                    duration_ms: duration_ms(packet.dur(), time_base, spec.rate),
+                    error: err.to_string(),
+                });
+                silence.resize(frames as usize * spec.channels.count(), 0.0);
+                &silence
             }
             Err(err) => return Err(err),
+        };
+
+        if !aggregate.push(spec, samples, sample_seconds) {
+            break;
         }
     }
     
-    Ok((spec, aggregate_samples))
-}
\ No newline at end of file
+    let (spec, aggregate_samples) = aggregate.finish();
+    Ok((spec, aggregate_samples, report))
+}
+
+/// Decoded samples in the format of the first decoded run. Runs decoded with another
+/// sample rate or channel count, such as the next stream of a chained Ogg file, are
+/// converted to it rather than being read with the wrong format.
+struct Aggregate {
+    /// Format of the first run, set once it ends
+    spec: Option<SignalSpec>,
+    samples: Vec<f32>,
+    run_spec: SignalSpec,
+    run: Vec<f32>,
+    /// Seconds of audio collected before the current run
+    seconds: f64,
+}
+
+impl Aggregate {
+    fn new(spec: SignalSpec) -> Aggregate {
+        Aggregate {
+            spec: None,
+            samples: Vec::new(),
+            run_spec: spec,
+            run: Vec::with_capacity(12 * spec.rate as usize * spec.channels.count()),
+            seconds: 0.0,
+        }
+    }
+
+    /// Adds interleaved samples, returning false once `max_seconds` have been collected.
+    fn push(&mut self, spec: SignalSpec, samples: &[f32], max_seconds: usize) -> bool {
+        if !same_format(spec, self.run_spec) {
+            self.end_run();
+            self.run_spec = spec;
+        }
+        let remaining_frames = ((max_seconds as f64 - self.seconds) * spec.rate as f64) as usize;
+        let max_samples = remaining_frames.saturating_mul(spec.channels.count());
+        if self.run.len() + samples.len() > max_samples {
+            // Calculate how many samples can still be added without exceeding the max size
+            self.run.extend_from_slice(&samples[..max_samples.saturating_sub(self.run.len())]);
+            return false;
+        }
+        self.run.extend_from_slice(samples);
+        true
+    }
+
+    fn end_run(&mut self) {
+        if self.run.is_empty() {
+            return;
+        }
+        let run_spec = self.run_spec;
+        let channel_count = run_spec.channels.count();
+        self.seconds += (self.run.len() / channel_count) as f64 / run_spec.rate as f64;
+        match self.spec {
+            None => {
+                self.spec = Some(run_spec);
+                self.samples = std::mem::take(&mut self.run);
+            }
+            Some(spec) => {
+                let target_channel_count = spec.channels.count();
+                let mono_samples = if spec.rate == run_spec.rate {
+                    downmix(&self.run, channel_count)
+                } else {
+                    resample_mono(run_spec.rate, channel_count, &self.run, spec.rate, ResampleQuality::default())
+                };
+                self.samples.reserve(mono_samples.len() * target_channel_count);
+                for sample in mono_samples {
+                    self.samples.extend(std::iter::repeat(sample).take(target_channel_count));
+                }
+                self.run.clear();
+            }
+        }
+    }
+
+    fn finish(mut self) -> (SignalSpec, Vec<f32>) {
+        self.end_run();
+        (self.spec.unwrap_or(self.run_spec), self.samples)
+    }
+}
+
+/// Whether samples of both formats can be concatenated as they are
+fn same_format(a: SignalSpec, b: SignalSpec) -> bool {
+    a.rate == b.rate && a.channels.count() == b.channels.count()
+}
+
+/// Length of a packet timestamp or duration in seconds, assuming a time base of one
+/// sample when the track has none
+fn duration_seconds(duration: u64, time_base: Option<TimeBase>, sample_rate: u32) -> f64 {
+    match time_base {
+        Some(time_base) if time_base.numer > 0 && time_base.denom > 0 => {
+            let time = time_base.calc_time(duration);
+            time.seconds as f64 + time.frac
+        }
+        _ => duration as f64 / sample_rate.max(1) as f64,
+    }
+}
+
+fn duration_ms(duration: u64, time_base: Option<TimeBase>, sample_rate: u32) -> f64 {
+    duration_seconds(duration, time_base, sample_rate) * 1000.0
+}
+
+fn duration_frames(duration: u64, time_base: Option<TimeBase>, sample_rate: u32) -> u64 {
+    (duration_seconds(duration, time_base, sample_rate) * sample_rate as f64).round() as u64
+}
+
+/// Decodes a whole recording into 16 KHz mono samples, averaging its channels. Used for
+/// reference audio, which is decoded with the default options whatever a query was decoded with.
+pub fn pcm_from_bytes(bytes: Vec<u8>) -> Result<Vec<i16>, Error> {
+    let (signal_spec, samples) = samples_from_bytes(bytes, usize::MAX)?;
+    if samples.is_empty() {
+        return Err(Error::DecodeError("no audio decoded"));
+    }
+    Ok(resample(signal_spec.rate, signal_spec.channels.count(), &samples, 16000))
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprinting::test_audio::{noise, peak_allocation, to_i16, wav};
+
+    #[test]
+    fn decodes_without_copying_the_input() {
+        // Five minutes of 8 KHz mono, 4.8 MB, of which a second is kept
+        let bytes = wav(8000, 1, &to_i16(&noise(1, 8000 * 300, 0.5)));
+        let length = bytes.len();
+        let (decoded, peak) = peak_allocation(|| samples_from_bytes(bytes, 1));
+        let (spec, samples) = decoded.unwrap();
+        assert_eq!((spec.rate, samples.len()), (8000, 8000));
+        // A copy of the input alone would be as large as it
+        assert!(peak < length / 4, "peak of {} bytes decoding {} bytes", peak, length);
+    }
+}
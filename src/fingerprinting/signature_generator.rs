@@ -1,7 +1,15 @@
 use crate::fingerprinting::hanning::HANNING_WINDOW_2048_MULTIPLIERS;
+use crate::fingerprinting::progress::CancellationToken;
 use chfft::RFft1D;
 use std::{cmp::Ordering, collections::HashMap};
 
+/// Samples of the signal each FFT pass advances by
+pub const SAMPLES_PER_FFT_PASS: usize = 128;
+
+/// FFT passes between two progress reports, a couple of seconds of audio at 16 KHz
+const PASSES_PER_PROGRESS: usize = 256;
+
+#[derive(Debug, Clone, Copy)]
 pub struct FrequencyPeak {
     pub fft_pass_number: u32,
     pub peak_magnitude: u16,
@@ -9,6 +17,14 @@ pub struct FrequencyPeak {
     pub sample_rate_hz: u32,
 }
 
+impl FrequencyPeak {
+    /// Frequency of the peak in Hz, given 1024 useful bins and the
+    /// multiplication by 64 made before storing the bin
+    pub fn frequency_hz(&self) -> f32 {
+        self.corrected_peak_frequency_bin as f32 * (self.sample_rate_hz as f32 / 2.0 / 1024.0 / 64.0)
+    }
+}
+
 #[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
 pub enum FrequencyBand {
     _250_520 = 0,
@@ -17,6 +33,30 @@ pub enum FrequencyBand {
     _3500_5500 = 3,
 }
 
+impl FrequencyBand {
+    pub const ALL: [FrequencyBand; 4] = [
+        FrequencyBand::_250_520,
+        FrequencyBand::_520_1450,
+        FrequencyBand::_1450_3500,
+        FrequencyBand::_3500_5500,
+    ];
+
+    pub fn from_index(index: u32) -> Option<FrequencyBand> {
+        FrequencyBand::ALL.get(index as usize).copied()
+    }
+
+    /// Band a frequency belongs to, `None` outside the 250 Hz-5.5 KHz range
+    pub fn from_hz(frequency_hz: f32) -> Option<FrequencyBand> {
+        match frequency_hz as i32 {
+            250..=519 => Some(FrequencyBand::_250_520),
+            520..=1449 => Some(FrequencyBand::_520_1450),
+            1450..=3499 => Some(FrequencyBand::_1450_3500),
+            3500..=5500 => Some(FrequencyBand::_3500_5500),
+            _ => None,
+        }
+    }
+}
+
 impl Ord for FrequencyBand {
     fn cmp(&self, other: &Self) -> Ordering {
         (*self as i32).cmp(&(*other as i32))
@@ -25,10 +65,38 @@ impl Ord for FrequencyBand {
 
 impl PartialOrd for FrequencyBand {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some((*self as i32).cmp(&(*other as i32)))
+        Some(self.cmp(other))
     }
 }
 
+/// Peaks within `[start, end)` FFT passes, moved by `shift` passes
+pub fn window_peaks(
+    frequency_band_to_sound_peaks: &HashMap<FrequencyBand, Vec<FrequencyPeak>>,
+    start: u32,
+    end: u32,
+    shift: i64,
+) -> HashMap<FrequencyBand, Vec<FrequencyPeak>> {
+    frequency_band_to_sound_peaks
+        .iter()
+        .map(|(frequency_band, frequency_peaks)| {
+            let window = frequency_peaks
+                .iter()
+                .filter(|peak| peak.fft_pass_number >= start && peak.fft_pass_number < end)
+                .filter_map(|peak| {
+                    let fft_pass_number = u32::try_from(peak.fft_pass_number as i64 + shift).ok()?;
+                    Some(FrequencyPeak { fft_pass_number, ..*peak })
+                })
+                .collect();
+            (*frequency_band, window)
+        })
+        .collect()
+}
+
+/// Rate of the audio the peaks were found in, `None` without peaks
+pub fn peaks_sample_rate(frequency_band_to_sound_peaks: &HashMap<FrequencyBand, Vec<FrequencyPeak>>) -> Option<u32> {
+    frequency_band_to_sound_peaks.values().flatten().next().map(|peak| peak.sample_rate_hz)
+}
+
 pub struct SignatureGenerator {
     ring_buffer_of_samples: Vec<i16>,
     reordered_ring_buffer_of_samples: Vec<f32>,
@@ -39,11 +107,31 @@ pub struct SignatureGenerator {
     fft_object: RFft1D<f32>,
     spread_fft_outputs_index: usize,
     num_spread_ffts_done: u32,
+    sample_rate_hz: u32,
 	frequency_band_to_sound_peaks: HashMap<FrequencyBand, Vec<FrequencyPeak>>,
 }
 
 impl SignatureGenerator {
-	pub fn frequency_band_to_sound_peaks(s16_mono_16khz_buffer: &Vec<i16>) -> HashMap<FrequencyBand, Vec<FrequencyPeak>> {
+	pub fn frequency_band_to_sound_peaks(s16_mono_16khz_buffer: &[i16]) -> HashMap<FrequencyBand, Vec<FrequencyPeak>> {
+		SignatureGenerator::frequency_band_to_sound_peaks_cancellable(s16_mono_16khz_buffer, &CancellationToken::new()).unwrap()
+	}
+
+	/// Like `frequency_band_to_sound_peaks`, returning `None` once the token is cancelled
+	pub fn frequency_band_to_sound_peaks_cancellable(
+		s16_mono_16khz_buffer: &[i16],
+		cancellation: &CancellationToken,
+	) -> Option<HashMap<FrequencyBand, Vec<FrequencyPeak>>> {
+		SignatureGenerator::frequency_band_to_sound_peaks_at(s16_mono_16khz_buffer, 16000, |_| !cancellation.is_cancelled())
+	}
+
+	/// Like `frequency_band_to_sound_peaks_cancellable`, for mono samples at any rate a signature
+	/// can be encoded at, such as 8 KHz telephone audio. `on_progress` is called with the share
+	/// of the samples done every few seconds of audio, and returning false stops with `None`.
+	pub fn frequency_band_to_sound_peaks_at(
+		s16_mono_buffer: &[i16],
+		sample_rate_hz: u32,
+		mut on_progress: impl FnMut(f32) -> bool,
+	) -> Option<HashMap<FrequencyBand, Vec<FrequencyPeak>>> {
 		let mut this = SignatureGenerator {
             ring_buffer_of_samples: vec![0i16; 2048],
             ring_buffer_of_samples_index: 0,
@@ -60,9 +148,15 @@ impl SignatureGenerator {
 
             num_spread_ffts_done: 0,
 
+            sample_rate_hz,
+
 			frequency_band_to_sound_peaks: HashMap::new(),
         };
-        for chunk in s16_mono_16khz_buffer.chunks_exact(128) {
+        let number_passes = s16_mono_buffer.len() / SAMPLES_PER_FFT_PASS;
+        for (pass, chunk) in s16_mono_buffer.chunks_exact(SAMPLES_PER_FFT_PASS).enumerate() {
+            if pass % PASSES_PER_PROGRESS == 0 && !on_progress(pass as f32 / number_passes as f32) {
+                return None;
+            }
             this.do_fft(chunk);
             this.do_peak_spreading();
             this.num_spread_ffts_done += 1;
@@ -70,12 +164,12 @@ impl SignatureGenerator {
                 this.do_peak_recognition();
             }
         }
-        return this.frequency_band_to_sound_peaks;
+        Some(this.frequency_band_to_sound_peaks)
 	}
 
-	fn do_fft(&mut self, s16_mono_16khz_chunk: &[i16]) {
+	fn do_fft(&mut self, s16_mono_chunk: &[i16]) {
         // Copy the 128 input s16le samples to the local ring buffer
-        self.ring_buffer_of_samples[self.ring_buffer_of_samples_index..self.ring_buffer_of_samples_index + 128].copy_from_slice(s16_mono_16khz_chunk);
+        self.ring_buffer_of_samples[self.ring_buffer_of_samples_index..self.ring_buffer_of_samples_index + 128].copy_from_slice(s16_mono_chunk);
 
         self.ring_buffer_of_samples_index += 128;
         self.ring_buffer_of_samples_index &= 2047;
@@ -198,20 +292,17 @@ impl SignatureGenerator {
 
                         assert!(peak_variation_1 >= 0.0);
 
-                        // Convert back a FFT bin to a frequency, given a 16 KHz sample
-                        // rate, 1024 useful bins and the multiplication by 64 made before
+                        // Convert back a FFT bin to a frequency, given the sample rate,
+                        // 1024 useful bins and the multiplication by 64 made before
                         // storing the information
-                        let frequency_hz: f32 = corrected_peak_frequency_bin as f32 * (16000.0 / 2.0 / 1024.0 / 64.0);
+                        let frequency_hz: f32 = corrected_peak_frequency_bin as f32 * (self.sample_rate_hz as f32 / 2.0 / 1024.0 / 64.0);
 
                         // Ignore peaks outside the 250 Hz-5.5 KHz range, store them into
                         // a lookup table that will be used to generate the binary fingerprint
                         // otherwise
-                        let frequency_band = match frequency_hz as i32 {
-                            250..=519 => FrequencyBand::_250_520,
-                            520..=1449 => FrequencyBand::_520_1450,
-                            1450..=3499 => FrequencyBand::_1450_3500,
-                            3500..=5500 => FrequencyBand::_3500_5500,
-                            _ => {
+                        let frequency_band = match FrequencyBand::from_hz(frequency_hz) {
+                            Some(frequency_band) => frequency_band,
+                            None => {
                                 continue;
                             }
                         };
@@ -228,7 +319,7 @@ impl SignatureGenerator {
                                 fft_pass_number,
                                 peak_magnitude: peak_magnitude as u16,
                                 corrected_peak_frequency_bin,
-                                sample_rate_hz: 16000,
+                                sample_rate_hz: self.sample_rate_hz,
                             });
                     }
                 }
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use crate::fingerprinting::compare::{compare_peaks, CompareOptions, SignatureComparison};
+use crate::fingerprinting::signature_generator::{FrequencyBand, FrequencyPeak};
+
+/// Most ratios searched either side of a grid's center
+const MAX_GRID_STEPS: f32 = 50.0;
+
+pub struct SkewOptions {
+    /// Largest speed or pitch change to search for, as a fraction (0.08 for ±8%)
+    pub max_skew: f32,
+    /// Step of the coarse search grid, as a fraction
+    pub coarse_step: f32,
+    /// Step of the fine search grid around the best coarse candidate, as a fraction
+    pub fine_step: f32,
+}
+
+impl SkewOptions {
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if !(self.max_skew.is_finite() && (0.0..1.0).contains(&self.max_skew)) {
+            return Err(format!("Invalid maxSkew {}, expected at least 0 and below 1", self.max_skew).into());
+        }
+        if !(self.coarse_step.is_finite() && self.coarse_step > 0.0 && self.fine_step.is_finite() && self.fine_step > 0.0) {
+            return Err("Skew search steps must be positive".into());
+        }
+        // Each grid compares every speed against every pitch, so its size grows with the square of the step count
+        if self.max_skew / self.coarse_step > MAX_GRID_STEPS || self.coarse_step / self.fine_step > MAX_GRID_STEPS {
+            return Err("Skew search steps are too small for the range searched".into());
+        }
+        Ok(())
+    }
+}
+
+impl Default for SkewOptions {
+    fn default() -> Self {
+        SkewOptions {
+            max_skew: 0.08,
+            coarse_step: 0.01,
+            fine_step: 0.0025,
+        }
+    }
+}
+
+#[wasm_bindgen]
+pub struct SkewedComparison {
+    score: f32,
+    offset_ms: i32,
+    speed_ratio: f32,
+    pitch_ratio: f32,
+    matched_peaks: u32,
+}
+
+#[wasm_bindgen]
+impl SkewedComparison {
+    /// Fraction of peaks matched at the best offset and ratios, between 0 and 1
+    #[wasm_bindgen(getter)]
+    pub fn score(&self) -> f32 {
+        self.score
+    }
+
+    /// Position in the reference the capture starts at
+    #[wasm_bindgen(getter, js_name = "offsetMs")]
+    pub fn offset_ms(&self) -> i32 {
+        self.offset_ms
+    }
+
+    /// How much faster the capture plays than the reference, 1.04 for 4% fast
+    #[wasm_bindgen(getter, js_name = "speedRatio")]
+    pub fn speed_ratio(&self) -> f32 {
+        self.speed_ratio
+    }
+
+    /// How much higher the capture sounds than the reference, 1.04 for 4% higher
+    #[wasm_bindgen(getter, js_name = "pitchRatio")]
+    pub fn pitch_ratio(&self) -> f32 {
+        self.pitch_ratio
+    }
+
+    /// Number of peaks matched at the best offset and ratios
+    #[wasm_bindgen(getter, js_name = "matchedPeaks")]
+    pub fn matched_peaks(&self) -> u32 {
+        self.matched_peaks
+    }
+}
+
+/// Maps the capture's peaks back onto the reference's time and frequency scale
+fn unskew_peaks(
+    frequency_band_to_sound_peaks: &HashMap<FrequencyBand, Vec<FrequencyPeak>>,
+    speed_ratio: f32,
+    pitch_ratio: f32,
+) -> HashMap<FrequencyBand, Vec<FrequencyPeak>> {
+    let mut unskewed: HashMap<FrequencyBand, Vec<FrequencyPeak>> = HashMap::new();
+    for peak in frequency_band_to_sound_peaks.values().flatten() {
+        let corrected_peak_frequency_bin = (peak.corrected_peak_frequency_bin as f32 / pitch_ratio).round();
+        if corrected_peak_frequency_bin > u16::MAX as f32 {
+            continue;
+        }
+        let unskewed_peak = FrequencyPeak {
+            fft_pass_number: (peak.fft_pass_number as f32 * speed_ratio).round() as u32,
+            corrected_peak_frequency_bin: corrected_peak_frequency_bin as u16,
+            ..*peak
+        };
+        // Scaling can move a peak into another band, or out of the fingerprinted range
+        if let Some(frequency_band) = FrequencyBand::from_hz(unskewed_peak.frequency_hz()) {
+            unskewed.entry(frequency_band).or_default().push(unskewed_peak);
+        }
+    }
+    for frequency_peaks in unskewed.values_mut() {
+        frequency_peaks.sort_by_key(|peak| peak.fft_pass_number);
+    }
+    unskewed
+}
+
+/// Ratios within `radius` of `center` in steps of `step`, kept within `1 ± max_skew`
+fn ratio_grid(center: f32, radius: f32, step: f32, max_skew: f32) -> Vec<f32> {
+    let steps = (radius / step).round() as i32;
+    (-steps..=steps)
+        .map(|index| center + index as f32 * step)
+        // A little slack, so the ends of the range survive rounding
+        .filter(|ratio| (ratio - 1.0).abs() <= max_skew + step * 1e-3)
+        .collect()
+}
+
+/// Compares a capture against a reference over a grid of speed and pitch ratios,
+/// so tracks played faster or slower (with or without keylock) still match.
+pub fn compare_skewed_peaks(
+    capture: &HashMap<FrequencyBand, Vec<FrequencyPeak>>,
+    reference: &HashMap<FrequencyBand, Vec<FrequencyPeak>>,
+    options: &SkewOptions,
+) -> Result<SkewedComparison, Box<dyn Error>> {
+    options.validate()?;
+    let compare_options = CompareOptions::default();
+    let compare = |speed_ratio: f32, pitch_ratio: f32| -> Result<(f32, f32, SignatureComparison), Box<dyn Error>> {
+        let unskewed = unskew_peaks(capture, speed_ratio, pitch_ratio);
+        Ok((speed_ratio, pitch_ratio, compare_peaks(&unskewed, reference, &compare_options)?))
+    };
+    let best_of = |candidates: Vec<(f32, f32)>| -> Result<(f32, f32, SignatureComparison), Box<dyn Error>> {
+        candidates
+            .into_iter()
+            .map(|(speed_ratio, pitch_ratio)| compare(speed_ratio, pitch_ratio))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .max_by(|x, y| {
+                // Prefer the ratios closest to unskewed on ties
+                let distance = |c: &(f32, f32, SignatureComparison)| (c.0 - 1.0).abs() + (c.1 - 1.0).abs();
+                x.2.matched_peak_count()
+                    .cmp(&y.2.matched_peak_count())
+                    .then(distance(y).total_cmp(&distance(x)))
+            })
+            .ok_or_else(|| "No speed or pitch ratio to search".into())
+    };
+
+    let grid = |speed_center: f32, pitch_center: f32, radius: f32, step: f32| {
+        let pitch_ratios = ratio_grid(pitch_center, radius, step, options.max_skew);
+        ratio_grid(speed_center, radius, step, options.max_skew)
+            .into_iter()
+            .flat_map(|speed_ratio| pitch_ratios.iter().map(move |pitch_ratio| (speed_ratio, *pitch_ratio)))
+            .collect::<Vec<_>>()
+    };
+
+    let (speed_ratio, pitch_ratio, _) = best_of(grid(1.0, 1.0, options.max_skew, options.coarse_step))?;
+    let (speed_ratio, pitch_ratio, comparison) =
+        best_of(grid(speed_ratio, pitch_ratio, options.coarse_step, options.fine_step))?;
+
+    Ok(SkewedComparison {
+        score: comparison.score(),
+        offset_ms: comparison.offset_ms(),
+        speed_ratio,
+        pitch_ratio,
+        matched_peaks: comparison.matched_peak_count(),
+    })
+}
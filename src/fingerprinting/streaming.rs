@@ -0,0 +1,439 @@
+use std::error::Error as StdError;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+
+use symphonia::core::audio::{SampleBuffer, SignalSpec};
+use symphonia::core::codecs::{Decoder, DecoderOptions};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::FormatReader;
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use wasm_bindgen::prelude::*;
+
+use crate::fingerprinting::decoded_signature::{check_sample_rate, DecodedSignature, DEFAULT_SAMPLE_RATE};
+use crate::fingerprinting::downmix::Downmix;
+use crate::fingerprinting::probe::{probe_format, FormatHint, ProbedFormat, TrackSelection};
+use crate::{stream_options_from_js, StreamingOptions};
+
+/// Bytes kept buffered ahead of the decoder until the input is finished, so a
+/// packet is never cut short by data that has not been pushed yet
+const READ_AHEAD_MARGIN: usize = 256 * 1024;
+
+/// Consumed bytes are dropped from the buffer once there are this many
+const DRAIN_THRESHOLD: usize = 1024 * 1024;
+
+/// Bytes buffered before giving up on finding the format, including formats that need
+/// seeking and wait for `finish`
+const MAX_PROBE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Whether bytes starting an MP4 file hold audio before the index (moov atom), which then
+/// can't be read until the whole file was buffered
+fn mp4_index_at_end(bytes: &[u8]) -> bool {
+    let mut position = 0;
+    while let Some(header) = bytes.get(position..position + 8) {
+        let size = u32::from_be_bytes(header[..4].try_into().unwrap()) as u64;
+        let size = match size {
+            // The size of the box follows as 64 bits
+            1 => match bytes.get(position + 8..position + 16) {
+                Some(large_size) => u64::from_be_bytes(large_size.try_into().unwrap()),
+                None => return false,
+            },
+            // The box extends to the end of the file
+            0 => u64::MAX,
+            size => size,
+        };
+        match &header[4..] {
+            b"ftyp" if position == 0 => {}
+            _ if position == 0 => return false,
+            b"moov" => return false,
+            b"mdat" | b"moof" => return true,
+            _ => {}
+        }
+        if size < 8 {
+            return false;
+        }
+        position = match usize::try_from(size).ok().and_then(|size| position.checked_add(size)) {
+            Some(position) => position,
+            None => return false,
+        };
+    }
+    false
+}
+
+/// How a `StreamingRecognizer` decodes the stream, as the options of the same name of `recognizeBytes`
+#[derive(Debug, Clone, Default)]
+pub struct StreamOptions {
+    /// Audio track to decode
+    pub track: TrackSelection,
+    /// Container format suggested by the file name or MIME type
+    pub hint: FormatHint,
+    /// How the decoded channels are mixed into the fingerprinted mono signal
+    pub downmix: Downmix,
+    /// Rate signatures are generated at, one of `SAMPLE_RATES`, 16 KHz when unset
+    pub sample_rate: Option<u32>,
+}
+
+/// Bytes pushed so far that the decoder has not consumed
+struct StreamBuffer {
+    bytes: Vec<u8>,
+    /// Position in the stream of `bytes[0]`
+    base: usize,
+    /// Position in the stream the source last read or sought to has reached
+    position: usize,
+    /// Whether consumed bytes must be kept, until the format has been probed
+    keep_consumed: bool,
+    finished: bool,
+}
+
+impl StreamBuffer {
+    fn buffered_ahead(&self) -> usize {
+        self.base + self.bytes.len() - self.position
+    }
+}
+
+/// A media source reading from bytes pushed as they arrive. Probing opens several at once, each
+/// with its own position, and the decoder keeps the last one.
+struct GrowableSource {
+    buffer: Arc<Mutex<StreamBuffer>>,
+    position: usize,
+}
+
+impl Read for GrowableSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buffer = self.buffer.lock().unwrap();
+        let start = self.position - buffer.base;
+        let length = buf.len().min(buffer.bytes.len() - start);
+        buf[..length].copy_from_slice(&buffer.bytes[start..start + length]);
+        self.position += length;
+        buffer.position = self.position;
+
+        if !buffer.keep_consumed && buffer.position - buffer.base >= DRAIN_THRESHOLD {
+            let consumed = buffer.position - buffer.base;
+            buffer.bytes.drain(..consumed);
+            buffer.base = buffer.position;
+        }
+        Ok(length)
+    }
+}
+
+impl Seek for GrowableSource {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let mut buffer = self.buffer.lock().unwrap();
+        let end = buffer.base + buffer.bytes.len();
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+            SeekFrom::End(offset) if buffer.finished => end as i64 + offset,
+            SeekFrom::End(_) => return Err(io::Error::new(io::ErrorKind::Unsupported, "stream length is not known yet")),
+        };
+        // Only bytes still buffered can be sought to
+        if position < buffer.base as i64 || position > end as i64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "position is not buffered"));
+        }
+        self.position = position as usize;
+        buffer.position = self.position;
+        Ok(position as u64)
+    }
+}
+
+impl MediaSource for GrowableSource {
+    /// Seekable once every byte was pushed, so formats indexed at the end (MP4) can be opened
+    fn is_seekable(&self) -> bool {
+        self.buffer.lock().unwrap().finished
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        let buffer = self.buffer.lock().unwrap();
+        buffer.finished.then(|| (buffer.base + buffer.bytes.len()) as u64)
+    }
+}
+
+/// Recognizes audio pushed in chunks, such as from a `ReadableStream`, decoding
+/// incrementally and returning each signature as soon as its window is complete.
+#[wasm_bindgen]
+pub struct StreamingRecognizer {
+    buffer: Arc<Mutex<StreamBuffer>>,
+    format: Option<Box<dyn FormatReader>>,
+    decoder: Option<Box<dyn Decoder>>,
+    track_id: u32,
+    /// Bytes to buffer before probing again, after the header was cut short
+    next_probe_length: usize,
+    /// Why probing failed with enough data, retried once seeking is possible in `finish`
+    probe_error: Option<Error>,
+    spec: Option<SignalSpec>,
+    /// Interleaved samples of the window being filled
+    window: Vec<f32>,
+    seconds: usize,
+    options: StreamOptions,
+    /// Rate signatures are generated at
+    sample_rate: u32,
+}
+
+#[wasm_bindgen]
+impl StreamingRecognizer {
+    /// # Arguments
+    /// * `seconds` - Seconds of audio in each signature, defaults to 12
+    /// * `options` - `{ track, hint, downmix, sampleRate }`, as the options of `recognizeBytes`. A bare `downmix` is accepted too
+    #[wasm_bindgen(constructor)]
+    pub fn new(seconds: Option<usize>, options: Option<StreamingOptions>) -> Result<StreamingRecognizer, JsValue> {
+        StreamingRecognizer::with_options(seconds, stream_options_from_js(options)?).map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// Adds the next chunk of the song file, returning the signatures completed by it.
+    #[wasm_bindgen(js_name = "pushBytes")]
+    pub fn push_bytes(&mut self, bytes: &[u8]) -> Result<Vec<DecodedSignature>, JsValue> {
+        self.push(bytes).map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// Decodes the rest of the song file, returning the remaining signatures.
+    pub fn finish(&mut self) -> Result<Vec<DecodedSignature>, JsValue> {
+        self.finish_stream().map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+}
+
+impl StreamingRecognizer {
+    /// Like the constructor, with parsed options
+    pub fn with_options(seconds: Option<usize>, options: StreamOptions) -> Result<StreamingRecognizer, Box<dyn StdError>> {
+        let sample_rate = check_sample_rate(options.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE))?;
+        Ok(StreamingRecognizer {
+            buffer: Arc::new(Mutex::new(StreamBuffer {
+                bytes: Vec::new(),
+                base: 0,
+                position: 0,
+                keep_consumed: true,
+                finished: false,
+            })),
+            format: None,
+            decoder: None,
+            track_id: 0,
+            next_probe_length: READ_AHEAD_MARGIN * 2,
+            probe_error: None,
+            spec: None,
+            window: Vec::new(),
+            seconds: seconds.unwrap_or(12).max(1),
+            options,
+            sample_rate,
+        })
+    }
+
+    /// Like `pushBytes`
+    pub fn push(&mut self, bytes: &[u8]) -> Result<Vec<DecodedSignature>, Box<dyn StdError>> {
+        {
+            let mut buffer = self.buffer.lock().unwrap();
+            if self.format.is_none() && buffer.bytes.len() + bytes.len() > MAX_PROBE_BYTES {
+                if mp4_index_at_end(&buffer.bytes) {
+                    return Err("MP4 files with their index (moov atom) after the audio can only be streamed up to 64 MiB, \
+                        move the index first with faststart, such as with `ffmpeg -i in.m4a -c copy -movflags +faststart out.m4a`"
+                        .into());
+                }
+                return Err(self.probe_error.take().unwrap_or(Error::Unsupported("format")).into());
+            }
+            buffer.bytes.extend_from_slice(bytes);
+        }
+        self.decode_available()
+    }
+
+    /// Like `finish`
+    pub fn finish_stream(&mut self) -> Result<Vec<DecodedSignature>, Box<dyn StdError>> {
+        self.buffer.lock().unwrap().finished = true;
+        let mut signatures = self.decode_available()?;
+        if self.format.is_none() {
+            return Err(Error::Unsupported("format").into());
+        }
+        signatures.extend(flush_window(&mut self.window, self.spec, self.options.downmix, self.sample_rate)?);
+        Ok(signatures)
+    }
+
+    /// Probes the format once enough bytes were pushed, starting over from the first
+    /// byte with twice as many while the header is cut short. Other errors are only
+    /// retried by `finish`, as formats indexed at the end (MP4) need every byte.
+    fn probe(&mut self) -> Result<bool, Error> {
+        let finished = {
+            let mut buffer = self.buffer.lock().unwrap();
+            let waiting = self.probe_error.is_some() || buffer.bytes.len() < self.next_probe_length;
+            if !buffer.finished && waiting {
+                return Ok(false);
+            }
+            buffer.position = 0;
+            buffer.finished
+        };
+        let open = || MediaSourceStream::new(Box::new(GrowableSource { buffer: self.buffer.clone(), position: 0 }), Default::default());
+        let ProbedFormat { format, .. } = match probe_format(open, &self.options.hint) {
+            Ok(probed) => probed,
+            Err(err) if finished => return Err(err),
+            // The header is larger than what was pushed so far, try again with more
+            Err(Error::IoError(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                let length = self.buffer.lock().unwrap().bytes.len();
+                if length >= MAX_PROBE_BYTES {
+                    return Err(Error::Unsupported("format"));
+                }
+                self.next_probe_length = length.saturating_mul(2).min(MAX_PROBE_BYTES);
+                return Ok(false);
+            }
+            Err(err) => {
+                self.probe_error = Some(err);
+                return Ok(false);
+            }
+        };
+
+        let track = self.options.track.select(format.as_ref())?;
+        self.decoder = Some(symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?);
+        self.track_id = track.id;
+        self.format = Some(format);
+        self.buffer.lock().unwrap().keep_consumed = false;
+        Ok(true)
+    }
+
+    fn decode_available(&mut self) -> Result<Vec<DecodedSignature>, Box<dyn StdError>> {
+        let mut signatures = Vec::new();
+        if self.format.is_none() && !self.probe()? {
+            return Ok(signatures);
+        }
+        let (Some(format), Some(decoder)) = (self.format.as_mut(), self.decoder.as_mut()) else {
+            return Ok(signatures);
+        };
+
+        let mut sample_buf: Option<SampleBuffer<f32>> = None;
+        loop {
+            // Stop short of bytes that were not pushed yet, a packet cut there could not be resumed
+            {
+                let buffer = self.buffer.lock().unwrap();
+                if !buffer.finished && buffer.buffered_ahead() < READ_AHEAD_MARGIN {
+                    break;
+                }
+            }
+            let packet = match format.next_packet() {
+                Ok(packet) => packet,
+                // The end of the stream once it is finished, the margin keeps it from being reached before
+                Err(Error::IoError(err)) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                // A new track chain starts, such as chained Ogg streams, which needs a new decoder
+                Err(Error::ResetRequired) => {
+                    let track = self.options.track.select(format.as_ref())?;
+                    *decoder = symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+                    self.track_id = track.id;
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            let audio_buf = decoder.decode(&packet)?;
+            let spec = *audio_buf.spec();
+            if self.spec.is_some_and(|window_spec| window_spec.rate != spec.rate || window_spec.channels.count() != spec.channels.count()) {
+                // The stream changed format, samples of the window can't be mixed with the new ones
+                signatures.extend(flush_window(&mut self.window, self.spec, self.options.downmix, self.sample_rate)?);
+                sample_buf = None;
+            }
+            // The sample buffer's capacity counts samples of every channel, the audio buffer's counts frames
+            if sample_buf.as_ref().map_or(true, |buf| buf.capacity() < audio_buf.capacity() * spec.channels.count()) {
+                sample_buf = Some(SampleBuffer::<f32>::new(audio_buf.capacity() as u64, spec));
+            }
+            let buf = sample_buf.as_mut().unwrap();
+            buf.copy_interleaved_ref(audio_buf);
+            self.spec = Some(spec);
+
+            let window_length = self.seconds * spec.rate as usize * spec.channels.count();
+            for sample in buf.samples() {
+                self.window.push(*sample);
+                if self.window.len() == window_length {
+                    signatures.extend(flush_window(&mut self.window, Some(spec), self.options.downmix, self.sample_rate)?);
+                }
+            }
+        }
+        Ok(signatures)
+    }
+}
+
+/// Signature of the samples of a window so far mixed down to mono, emptying it.
+fn flush_window(window: &mut Vec<f32>, spec: Option<SignalSpec>, downmix: Downmix, sample_rate: u32) -> Result<Option<DecodedSignature>, Box<dyn StdError>> {
+    let Some(spec) = spec.filter(|_| !window.is_empty()) else {
+        return Ok(None);
+    };
+    let mono_samples = downmix.apply(window, spec.channels)?;
+    window.clear();
+    Ok(Some(DecodedSignature::from_samples(mono_samples, spec.rate, 1, sample_rate)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprinting::signature_generator::peaks_sample_rate;
+    use crate::fingerprinting::test_audio::{noise, to_i16, wav};
+
+    fn mp4_box(kind: &[u8; 4], size: u32) -> Vec<u8> {
+        let mut bytes = size.to_be_bytes().to_vec();
+        bytes.extend_from_slice(kind);
+        bytes.resize(size.min(64) as usize, 0);
+        bytes
+    }
+
+    #[test]
+    fn emits_a_signature_per_window() {
+        let bytes = wav(16000, 2, &to_i16(&noise(1, 16000 * 2 * 30, 0.3)));
+        let mut recognizer = StreamingRecognizer::with_options(Some(12), StreamOptions::default()).unwrap();
+        let mut signatures = Vec::new();
+        for chunk in bytes.chunks(100_000) {
+            signatures.extend(recognizer.push(chunk).unwrap());
+        }
+        let pushed = signatures.len();
+        signatures.extend(recognizer.finish_stream().unwrap());
+        let durations: Vec<u32> = signatures.iter_mut().map(|signature| signature.samplems()).collect();
+        assert!(pushed >= 1);
+        assert_eq!(durations, [12000, 12000, 6000]);
+    }
+
+    #[test]
+    fn finds_mp4_indexes_after_the_audio() {
+        let ftyp = mp4_box(b"ftyp", 16);
+        let layout = |boxes: &[Vec<u8>]| boxes.concat();
+        assert!(!mp4_index_at_end(&layout(&[ftyp.clone(), mp4_box(b"moov", 32), mp4_box(b"mdat", 1 << 20)])));
+        assert!(mp4_index_at_end(&layout(&[ftyp.clone(), mp4_box(b"free", 24), mp4_box(b"mdat", 1 << 20)])));
+        // 64-bit box size
+        let mut large_mdat = 1u32.to_be_bytes().to_vec();
+        large_mdat.extend_from_slice(b"mdat");
+        large_mdat.extend_from_slice(&(5u64 << 30).to_be_bytes());
+        assert!(mp4_index_at_end(&layout(&[ftyp.clone(), large_mdat])));
+        assert!(!mp4_index_at_end(&layout(&[mp4_box(b"free", 24), mp4_box(b"mdat", 64)])));
+        assert!(!mp4_index_at_end(&ftyp[..12]));
+    }
+
+    #[test]
+    fn asks_for_faststart_when_an_mp4_index_is_out_of_reach() {
+        let mut recognizer = StreamingRecognizer::with_options(None, StreamOptions::default()).unwrap();
+        let mut header = mp4_box(b"ftyp", 16);
+        header[8..12].copy_from_slice(b"M4A ");
+        header.extend_from_slice(&(100u32 << 20).to_be_bytes());
+        header.extend_from_slice(b"mdat");
+        recognizer.push(&header).unwrap();
+
+        let chunk = vec![0u8; 4 << 20];
+        let error = (0..32).find_map(|_| recognizer.push(&chunk).err()).expect("pushing 128 MiB without an index");
+        assert!(error.to_string().contains("faststart"), "{}", error);
+    }
+
+    #[test]
+    fn generates_signatures_with_the_options_asked_for() {
+        let bytes = wav(16000, 1, &to_i16(&noise(1, 16000 * 5, 0.3)));
+        let options = StreamOptions { hint: FormatHint::parse("song.wav"), sample_rate: Some(8000), ..StreamOptions::default() };
+        let mut recognizer = StreamingRecognizer::with_options(None, options).unwrap();
+        recognizer.push(&bytes).unwrap();
+        let mut signatures = recognizer.finish_stream().unwrap();
+        assert_eq!(signatures.len(), 1);
+        assert_eq!(signatures[0].samplems(), 5000);
+        assert_eq!(peaks_sample_rate(signatures[0].frequency_band_to_sound_peaks()), Some(8000));
+
+        let options = StreamOptions { track: TrackSelection::Index(1), ..StreamOptions::default() };
+        let mut recognizer = StreamingRecognizer::with_options(None, options).unwrap();
+        recognizer.push(&bytes).unwrap();
+        assert!(recognizer.finish_stream().err().unwrap().to_string().contains("no track matches"));
+    }
+
+    #[test]
+    fn rejects_unsupported_sample_rates() {
+        for sample_rate in [0, 22050, 96000] {
+            let options = StreamOptions { sample_rate: Some(sample_rate), ..StreamOptions::default() };
+            assert!(StreamingRecognizer::with_options(None, options).is_err(), "{} Hz", sample_rate);
+        }
+    }
+}
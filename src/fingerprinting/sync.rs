@@ -0,0 +1,195 @@
+use std::error::Error;
+
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use crate::fingerprinting::compare::{compare_peaks, CompareOptions};
+use crate::fingerprinting::signature_generator::{window_peaks, SignatureGenerator};
+
+const SAMPLE_RATE_HZ: usize = 16000;
+
+/// Length of the windows used to estimate drift, in FFT passes (30 seconds)
+const DRIFT_WINDOW_PASSES: u32 = 30 * SAMPLE_RATE_HZ as u32 / 128;
+
+/// Length of audio cross-correlated when refining an offset, in samples (2 seconds)
+const REFINE_LENGTH: usize = 2 * SAMPLE_RATE_HZ;
+
+/// Lags searched either side of the coarse offset when refining, in samples
+const REFINE_RADIUS: i64 = 160;
+
+/// Minimum number of matched peaks for a drift window to be used
+const MIN_WINDOW_MATCHES: u32 = 10;
+
+/// Largest offset searched either way unless asked otherwise. Without a bound every pair of
+/// peaks of similar frequency is compared, which grows with the product of both lengths.
+pub const DEFAULT_MAX_OFFSET_MS: u32 = 5 * 60 * 1000;
+
+#[wasm_bindgen]
+pub struct SyncResult {
+    offset_ms: f64,
+    confidence: f32,
+    correlation: f32,
+    drift_ppm: f64,
+    windows: usize,
+}
+
+#[wasm_bindgen]
+impl SyncResult {
+    /// Offset of the second recording relative to the first at the start of the first, positive when the shared audio occurs later in the second
+    #[wasm_bindgen(getter, js_name = "offsetMs")]
+    pub fn offset_ms(&self) -> f64 {
+        self.offset_ms
+    }
+
+    /// Fraction of peaks matched at the offset, between 0 and 1
+    #[wasm_bindgen(getter)]
+    pub fn confidence(&self) -> f32 {
+        self.confidence
+    }
+
+    /// Normalized cross-correlation of the waveforms at the refined offset, between -1 and 1
+    #[wasm_bindgen(getter)]
+    pub fn correlation(&self) -> f32 {
+        self.correlation
+    }
+
+    /// How much the offset grows per second of the first recording, in parts per million
+    #[wasm_bindgen(getter, js_name = "driftPpm")]
+    pub fn drift_ppm(&self) -> f64 {
+        self.drift_ppm
+    }
+
+    /// Number of windows the drift was estimated from
+    #[wasm_bindgen(getter)]
+    pub fn windows(&self) -> usize {
+        self.windows
+    }
+}
+
+/// Refines a lag in samples by cross-correlating `a` from `start` against `b` within `radius` samples of `lag`,
+/// returning the fractional lag and its normalized correlation
+fn refine_lag(a: &[i16], b: &[i16], start: usize, lag: i64, radius: i64) -> Option<(f64, f32)> {
+    let end = (start + REFINE_LENGTH).min(a.len());
+    let correlate = |lag: i64| -> f32 {
+        let (mut sum, mut a_energy, mut b_energy) = (0f64, 0f64, 0f64);
+        for (index, a_sample) in a.iter().enumerate().take(end).skip(start) {
+            let b_index = index as i64 + lag;
+            if b_index < 0 || b_index as usize >= b.len() {
+                continue;
+            }
+            let (x, y) = (*a_sample as f64, b[b_index as usize] as f64);
+            sum += x * y;
+            a_energy += x * x;
+            b_energy += y * y;
+        }
+        if a_energy == 0.0 || b_energy == 0.0 {
+            return 0.0;
+        }
+        (sum / (a_energy * b_energy).sqrt()) as f32
+    };
+
+    let correlations: Vec<(i64, f32)> = (lag - radius..=lag + radius).map(|l| (l, correlate(l))).collect();
+    let best = (0..correlations.len()).max_by(|x, y| correlations[*x].1.total_cmp(&correlations[*y].1))?;
+    let (best_lag, best_correlation) = correlations[best];
+    if best_correlation <= 0.0 {
+        return None;
+    }
+
+    // Parabolic interpolation between the neighbouring lags for a sub-sample estimate
+    let mut fraction = 0.0;
+    if best > 0 && best + 1 < correlations.len() {
+        let (before, after) = (correlations[best - 1].1 as f64, correlations[best + 1].1 as f64);
+        let denominator = before - 2.0 * best_correlation as f64 + after;
+        if denominator < 0.0 {
+            fraction = (0.5 * (before - after) / denominator).clamp(-0.5, 0.5);
+        }
+    }
+    Some((best_lag as f64 + fraction, best_correlation))
+}
+
+/// Finds the time offset between two 16 KHz mono recordings of the same content, no larger than
+/// `max_offset_ms` either way, `DEFAULT_MAX_OFFSET_MS` when unset.
+pub fn sync_offset(a: &[i16], b: &[i16], max_offset_ms: Option<u32>) -> Result<SyncResult, Box<dyn Error>> {
+    let a_peaks = SignatureGenerator::frequency_band_to_sound_peaks(a);
+    let b_peaks = SignatureGenerator::frequency_band_to_sound_peaks(b);
+
+    // Coarse offset over the whole recordings, in steps of one FFT pass (128 samples)
+    let comparison = compare_peaks(&a_peaks, &b_peaks, &CompareOptions {
+        max_offset_ms: Some(max_offset_ms.unwrap_or(DEFAULT_MAX_OFFSET_MS)),
+        ..CompareOptions::default()
+    })?;
+    if comparison.matched_peak_count() == 0 {
+        return Err("No common audio found between the recordings".into());
+    }
+    let coarse_passes = comparison.offset_fft_passes();
+
+    // Estimate the offset over consecutive windows of the first recording to measure drift
+    let window_options = CompareOptions {
+        max_offset_ms: Some(500),
+        ..CompareOptions::default()
+    };
+    let total_passes = (a.len() / 128) as u32;
+    let mut points: Vec<(f64, f64, f32)> = Vec::new();
+    let mut window_start = 0;
+    while window_start < total_passes {
+        let window_end = (window_start + DRIFT_WINDOW_PASSES).min(total_passes);
+        let window = window_peaks(&a_peaks, window_start, window_end, coarse_passes);
+        let residual = compare_peaks(&window, &b_peaks, &window_options)?;
+        if residual.matched_peak_count() >= MIN_WINDOW_MATCHES {
+            let lag = (coarse_passes + residual.offset_fft_passes()) * 128;
+            let start = window_start as usize * 128;
+            if let Some((refined, correlation)) = refine_lag(a, b, start, lag, REFINE_RADIUS) {
+                let center = (start + (window_end as usize * 128).min(start + REFINE_LENGTH)) as f64 / 2.0;
+                points.push((center, refined, correlation));
+            }
+        }
+        window_start = window_end;
+    }
+
+    // Least squares fit of lag against position in the first recording
+    let (lag_at_start, slope, correlation) = match points.len() {
+        0 => {
+            let (refined, correlation) = refine_lag(a, b, 0, coarse_passes * 128, REFINE_RADIUS)
+                .unwrap_or((coarse_passes as f64 * 128.0, 0.0));
+            (refined, 0.0, correlation)
+        }
+        1 => (points[0].1, 0.0, points[0].2),
+        count => {
+            let count = count as f64;
+            let mean_x = points.iter().map(|p| p.0).sum::<f64>() / count;
+            let mean_y = points.iter().map(|p| p.1).sum::<f64>() / count;
+            let covariance: f64 = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+            let variance: f64 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+            let slope = if variance > 0.0 { covariance / variance } else { 0.0 };
+            let correlation = points.iter().map(|p| p.2).sum::<f32>() / count as f32;
+            (mean_y - slope * mean_x, slope, correlation)
+        }
+    };
+
+    Ok(SyncResult {
+        offset_ms: lag_at_start * 1000.0 / SAMPLE_RATE_HZ as f64,
+        confidence: comparison.score(),
+        correlation,
+        drift_ppm: slope * 1_000_000.0,
+        windows: points.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprinting::test_audio::{noise, to_i16};
+
+    #[test]
+    fn recovers_a_known_offset() {
+        let a = to_i16(&noise(1, SAMPLE_RATE_HZ * 12, 0.3));
+        // The same audio 1.5 seconds later, after unrelated noise
+        let mut b = to_i16(&noise(2, SAMPLE_RATE_HZ * 3 / 2, 0.3));
+        b.extend_from_slice(&a);
+
+        let result = sync_offset(&a, &b, None).unwrap();
+        assert!((result.offset_ms() - 1500.0).abs() < 0.1, "{}", result.offset_ms());
+        assert!(result.correlation() > 0.99);
+        let result = sync_offset(&b, &a, None).unwrap();
+        assert!((result.offset_ms() + 1500.0).abs() < 0.1, "{}", result.offset_ms());
+    }
+}
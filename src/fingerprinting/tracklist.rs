@@ -0,0 +1,404 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Write;
+
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use crate::fingerprinting::index::{FingerprintIndex, IndexMatch, INDEX_SAMPLE_RATE};
+use crate::fingerprinting::signature_generator::{window_peaks, FrequencyBand, FrequencyPeak, SAMPLES_PER_FFT_PASS};
+
+/// How far apart the offsets a window and its slot match a track at can be, for the slot to hold it
+const SLOT_OFFSET_TOLERANCE_MS: i32 = 100;
+
+pub struct TracklistOptions {
+    /// Minimum number of landmarks a window must match for its track to be identified
+    pub min_matched_landmarks: u32,
+    /// Length of the windows matched against the index
+    pub window_ms: u32,
+    /// Distance between the starts of overlapping windows, how precisely track changes are placed
+    pub hop_ms: u32,
+    /// Longest stretch of unidentified audio bridged between two hits of the same track
+    pub max_gap_ms: u32,
+}
+
+impl Default for TracklistOptions {
+    fn default() -> Self {
+        TracklistOptions {
+            min_matched_landmarks: 10,
+            window_ms: 12000,
+            hop_ms: 3000,
+            max_gap_ms: 12000,
+        }
+    }
+}
+
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Debug, Clone)]
+pub struct TracklistEntry {
+    /// Id of the identified track, `None` for a gap of unknown audio
+    #[wasm_bindgen(js_name = "trackId")]
+    pub track_id: Option<u32>,
+    pub name: String,
+    #[wasm_bindgen(js_name = "startMs")]
+    pub start_ms: u32,
+    #[wasm_bindgen(js_name = "endMs")]
+    pub end_ms: u32,
+    /// Position in the track at the start of the entry
+    #[wasm_bindgen(js_name = "trackOffsetMs")]
+    pub track_offset_ms: i32,
+    /// Average score of the windows the track was identified in, between 0 and 1
+    pub confidence: f32,
+}
+
+#[wasm_bindgen]
+pub struct Tracklist {
+    entries: Vec<TracklistEntry>,
+}
+
+/// Stretch of the recording at the center of a window, labelled with the window's best match
+struct Window {
+    start_ms: u32,
+    end_ms: u32,
+    /// Id of the track and position in it at `start_ms`
+    track: Option<(u32, i32)>,
+    /// Score of the match, `None` for windows bridged between two hits
+    score: Option<f32>,
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for character in value.chars() {
+        match character {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// CUE sheet string: quotes can't be escaped, so they become single quotes, and line breaks spaces
+fn cue_string(value: &str) -> String {
+    let sanitized: String = value
+        .chars()
+        .map(|character| match character {
+            '"' => '\'',
+            c if c.is_control() => ' ',
+            c => c,
+        })
+        .collect();
+    format!("\"{}\"", sanitized)
+}
+
+/// CUE sheet time, in minutes, seconds and frames of 1/75s
+fn cue_time(ms: u32) -> String {
+    let frames = ms as u64 * 75 / 1000;
+    format!("{:02}:{:02}:{:02}", frames / 75 / 60, frames / 75 % 60, frames % 75)
+}
+
+#[wasm_bindgen]
+impl Tracklist {
+    /// Identified tracks and unknown gaps in order
+    pub fn entries(&self) -> Vec<TracklistEntry> {
+        self.entries.clone()
+    }
+
+    /// Tracklist as a JSON array of entries.
+    #[wasm_bindgen(js_name = "toJson")]
+    pub fn to_json(&self) -> String {
+        let entries: Vec<String> = self
+            .entries
+            .iter()
+            .map(|entry| {
+                format!(
+                    "{{\"trackId\":{},\"name\":{},\"startMs\":{},\"endMs\":{},\"trackOffsetMs\":{},\"confidence\":{}}}",
+                    entry.track_id.map_or("null".to_string(), |id| id.to_string()),
+                    json_string(&entry.name),
+                    entry.start_ms,
+                    entry.end_ms,
+                    entry.track_offset_ms,
+                    entry.confidence,
+                )
+            })
+            .collect();
+        format!("[{}]", entries.join(","))
+    }
+
+    /// Tracklist as a CUE sheet for the recording `file_name`.
+    #[wasm_bindgen(js_name = "toCue")]
+    pub fn to_cue(&self, file_name: &str) -> String {
+        let mut cue = String::new();
+        writeln!(cue, "FILE {} WAVE", cue_string(file_name)).unwrap();
+        for (index, entry) in self.entries.iter().enumerate() {
+            let title = if entry.track_id.is_some() { entry.name.as_str() } else { "Unknown" };
+            writeln!(cue, "  TRACK {:02} AUDIO", index + 1).unwrap();
+            writeln!(cue, "    TITLE {}", cue_string(title)).unwrap();
+            writeln!(cue, "    INDEX 01 {}", cue_time(entry.start_ms)).unwrap();
+        }
+        cue
+    }
+}
+
+/// Identifies the tracks of a long recording from its peaks, matching overlapping windows a
+/// hop apart so track changes are placed to within about a hop. Each window labels the hop at
+/// its center; consecutive hits of a track are merged and the gaps in between flagged. The peaks
+/// must have been found at the index's rate.
+pub fn generate_tracklist(
+    frequency_band_to_sound_peaks: &HashMap<FrequencyBand, Vec<FrequencyPeak>>,
+    duration_ms: u32,
+    index: &FingerprintIndex,
+    options: &TracklistOptions,
+) -> Result<Tracklist, Box<dyn Error>> {
+    let to_passes = |ms: u32| (ms as u64 * INDEX_SAMPLE_RATE as u64 / SAMPLES_PER_FFT_PASS as u64 / 1000) as u32;
+
+    let window_ms = options.window_ms.max(1);
+    let hop_ms = options.hop_ms.clamp(1, window_ms);
+    let window_count = match duration_ms.checked_sub(window_ms) {
+        Some(remaining_ms) if remaining_ms > 0 => remaining_ms.div_ceil(hop_ms) as usize + 1,
+        _ => 1,
+    };
+    // The last window is moved back so it ends with the recording
+    let window_starts: Vec<u32> = (0..window_count)
+        .map(|window| (window as u32 * hop_ms).min(duration_ms.saturating_sub(window_ms)))
+        .collect();
+
+    let mut windows: Vec<Window> = Vec::with_capacity(window_count);
+    for (window, window_start_ms) in window_starts.iter().copied().enumerate() {
+        let start_ms = if window == 0 { 0 } else { window_start_ms + (window_ms - hop_ms) / 2 };
+        let end_ms = match window_starts.get(window + 1) {
+            Some(next_start_ms) => next_start_ms + (window_ms - hop_ms) / 2,
+            None => duration_ms,
+        };
+        if end_ms <= start_ms {
+            continue;
+        }
+        let start_pass = to_passes(window_start_ms);
+        let peaks = window_peaks(frequency_band_to_sound_peaks, start_pass, to_passes(window_start_ms + window_ms), -(start_pass as i64));
+        // A window overlapping the start of a track matches it before its slot reaches the track
+        let best = index
+            .query(&peaks)?
+            .into_iter()
+            .next()
+            .filter(|best| best.matched_landmarks >= options.min_matched_landmarks)
+            .filter(|best| best.offset_ms + (end_ms - window_start_ms) as i32 > 0);
+        // One still overlapping the end of a track matches it after it ended, so the slot itself must hold it
+        let slot_holds = |best: &IndexMatch| -> Result<bool, Box<dyn Error>> {
+            let slot_pass = to_passes(start_ms);
+            let slot_peaks = window_peaks(frequency_band_to_sound_peaks, slot_pass, to_passes(end_ms), -(slot_pass as i64));
+            let slot_offset_ms = best.offset_ms + (start_ms - window_start_ms) as i32;
+            Ok(index
+                .query(&slot_peaks)?
+                .iter()
+                .any(|slot_match| slot_match.track_id == best.track_id && (slot_match.offset_ms - slot_offset_ms).abs() <= SLOT_OFFSET_TOLERANCE_MS))
+        };
+        let best = match best {
+            Some(best) if slot_holds(&best)? => Some(best),
+            _ => None,
+        };
+        windows.push(Window {
+            start_ms,
+            end_ms,
+            track: best.as_ref().map(|best| (best.track_id, best.offset_ms + (start_ms - window_start_ms) as i32)),
+            score: best.map(|best| best.score),
+        });
+    }
+
+    // Bridge short gaps between two hits of the same track
+    for index in 0..windows.len() {
+        let Some((track_id, _)) = windows[index].track else {
+            continue;
+        };
+        let Some(gap) = windows[index + 1..].iter().position(|window| window.track.is_some()) else {
+            continue;
+        };
+        let gap_ms: u32 = windows[index + 1..index + 1 + gap].iter().map(|window| window.end_ms - window.start_ms).sum();
+        if gap > 0 && gap_ms <= options.max_gap_ms && windows[index + 1 + gap].track.map(|track| track.0) == Some(track_id) {
+            for window in &mut windows[index + 1..index + 1 + gap] {
+                window.track = Some((track_id, 0));
+            }
+        }
+    }
+
+    let mut entries: Vec<TracklistEntry> = Vec::new();
+    let mut scores: Vec<f32> = Vec::new();
+    for window in windows {
+        let track_id = window.track.map(|track| track.0);
+        match entries.last_mut() {
+            Some(entry) if entry.track_id == track_id => {
+                entry.end_ms = window.end_ms;
+                if let Some(score) = window.score {
+                    scores.push(score);
+                    entry.confidence = scores.iter().sum::<f32>() / scores.len() as f32;
+                }
+            }
+            _ => {
+                // Move the start of a track identified ahead of its slot to where it begins
+                let mut start_ms = window.start_ms;
+                let mut track_offset_ms = window.track.map_or(0, |track| track.1);
+                if track_offset_ms < 0 {
+                    let shift_ms = (-track_offset_ms as u32).min(window.end_ms - window.start_ms);
+                    match entries.last_mut() {
+                        Some(entry) => entry.end_ms += shift_ms,
+                        None => entries.push(TracklistEntry {
+                            track_id: None,
+                            name: String::new(),
+                            start_ms,
+                            end_ms: start_ms + shift_ms,
+                            track_offset_ms: 0,
+                            confidence: 0.0,
+                        }),
+                    }
+                    start_ms += shift_ms;
+                    track_offset_ms = 0;
+                }
+                scores.clear();
+                scores.extend(window.score);
+                entries.push(TracklistEntry {
+                    track_id,
+                    name: track_id
+                        .and_then(|id| index.tracks().into_iter().find(|track| track.id == id))
+                        .map(|track| track.name.clone())
+                        .unwrap_or_default(),
+                    start_ms,
+                    end_ms: window.end_ms,
+                    track_offset_ms,
+                    confidence: scores.first().copied().unwrap_or(0.0),
+                });
+            }
+        }
+    }
+    Ok(Tracklist { entries })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::OnceLock;
+
+    use crate::fingerprinting::signature_generator::{SignatureGenerator, SAMPLES_PER_FFT_PASS};
+    use crate::fingerprinting::test_audio::{noise, to_i16};
+
+    const SAMPLE_RATE: usize = 16000;
+
+    /// Peaks of two 20 second references and of 20 seconds of audio missing from the index
+    fn reference_peaks() -> &'static [HashMap<FrequencyBand, Vec<FrequencyPeak>>; 3] {
+        static PEAKS: OnceLock<[HashMap<FrequencyBand, Vec<FrequencyPeak>>; 3]> = OnceLock::new();
+        PEAKS.get_or_init(|| [1, 2, 99].map(|seed| SignatureGenerator::frequency_band_to_sound_peaks(&to_i16(&noise(seed, SAMPLE_RATE * 20, 0.3)))))
+    }
+
+    /// Index of the references and the peaks of a recording playing `parts` in turn: a reference
+    /// (0 for unknown audio), where it starts and how long it plays, in seconds
+    fn recording(parts: &[(usize, u32, u32)]) -> (FingerprintIndex, HashMap<FrequencyBand, Vec<FrequencyPeak>>, u32) {
+        let references = reference_peaks();
+        let mut index = FingerprintIndex::new();
+        index.add_track("first \"mix\"", &references[0], 20000).unwrap();
+        index.add_track("second", &references[1], 20000).unwrap();
+
+        let to_passes = |seconds: u32| seconds * SAMPLE_RATE as u32 / SAMPLES_PER_FFT_PASS as u32;
+        let mut peaks: HashMap<FrequencyBand, Vec<FrequencyPeak>> = HashMap::new();
+        let mut position = 0;
+        for (reference, start, seconds) in parts.iter().copied() {
+            let source = &references[(reference + 2) % 3];
+            let shift = to_passes(position) as i64 - to_passes(start) as i64;
+            for (band, band_peaks) in window_peaks(source, to_passes(start), to_passes(start + seconds), shift) {
+                peaks.entry(band).or_default().extend(band_peaks);
+            }
+            position += seconds;
+        }
+        (index, peaks, position * 1000)
+    }
+
+    fn timeline(tracklist: &Tracklist) -> Vec<(Option<u32>, u32, u32)> {
+        tracklist.entries.iter().map(|entry| (entry.track_id, entry.start_ms, entry.end_ms)).collect()
+    }
+
+    fn assert_near(actual_ms: u32, expected_ms: u32, tolerance_ms: u32) {
+        assert!(actual_ms.abs_diff(expected_ms) <= tolerance_ms, "{} ms instead of {} ms", actual_ms, expected_ms);
+    }
+
+    #[test]
+    fn places_track_changes_within_a_hop() {
+        let (index, peaks, duration_ms) = recording(&[(1, 0, 20), (2, 5, 15)]);
+        let tracklist = generate_tracklist(&peaks, duration_ms, &index, &TracklistOptions::default()).unwrap();
+        let entries = timeline(&tracklist);
+        assert_eq!(entries.iter().map(|entry| entry.0).collect::<Vec<_>>(), [Some(0), Some(1)], "{:?}", entries);
+        assert_eq!((entries[0].1, entries[1].2), (0, duration_ms));
+        assert_eq!(entries[0].2, entries[1].1);
+        assert_near(entries[1].1, 20000, 3000);
+        assert_near(tracklist.entries[1].track_offset_ms as u32, 5000 + entries[1].1 - 20000, 200);
+        assert_eq!(tracklist.entries[0].name, "first \"mix\"");
+        assert!(tracklist.entries.iter().all(|entry| entry.confidence > 0.0 && entry.confidence <= 1.0));
+    }
+
+    #[test]
+    fn flags_unknown_audio_between_tracks() {
+        let (index, peaks, duration_ms) = recording(&[(1, 0, 15), (0, 0, 15), (2, 0, 15)]);
+        let tracklist = generate_tracklist(&peaks, duration_ms, &index, &TracklistOptions::default()).unwrap();
+        let entries = timeline(&tracklist);
+        assert_eq!(entries.iter().map(|entry| entry.0).collect::<Vec<_>>(), [Some(0), None, Some(1)], "{:?}", entries);
+        assert_near(entries[1].1, 15000, 3000);
+        assert_near(entries[2].1, 30000, 3000);
+        assert_eq!(tracklist.entries[1].confidence, 0.0);
+    }
+
+    #[test]
+    fn bridges_short_gaps_in_a_track() {
+        let (index, peaks, duration_ms) = recording(&[(1, 0, 20)]);
+        // Peaks dropped for a few seconds, as under loud crowd noise
+        let peaks = peaks
+            .into_iter()
+            .map(|(band, band_peaks)| (band, band_peaks.into_iter().filter(|peak| !(1000..1400).contains(&peak.fft_pass_number)).collect()))
+            .collect();
+        let tracklist = generate_tracklist(&peaks, duration_ms, &index, &TracklistOptions::default()).unwrap();
+        assert_eq!(timeline(&tracklist), [(Some(0), 0, duration_ms)]);
+    }
+
+    fn tracklist() -> Tracklist {
+        let entry = |track_id: Option<u32>, name: &str, start_ms: u32, end_ms: u32| TracklistEntry {
+            track_id,
+            name: name.to_string(),
+            start_ms,
+            end_ms,
+            track_offset_ms: 0,
+            confidence: if track_id.is_some() { 0.5 } else { 0.0 },
+        };
+        Tracklist {
+            entries: vec![
+                entry(Some(3), "Say \"hi\"\n\\o/", 0, 61000),
+                entry(None, "", 61000, 62500),
+                entry(Some(4), "Tab\tend", 62500, 3_723_040),
+            ],
+        }
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(
+            tracklist().to_json(),
+            concat!(
+                r#"[{"trackId":3,"name":"Say \"hi\"\n\\o/","startMs":0,"endMs":61000,"trackOffsetMs":0,"confidence":0.5},"#,
+                r#"{"trackId":null,"name":"","startMs":61000,"endMs":62500,"trackOffsetMs":0,"confidence":0},"#,
+                r#"{"trackId":4,"name":"Tab\tend","startMs":62500,"endMs":3723040,"trackOffsetMs":0,"confidence":0.5}]"#,
+            )
+        );
+    }
+
+    #[test]
+    fn writes_cue_sheets_without_breaking_quotes_or_lines() {
+        assert_eq!(
+            tracklist().to_cue("my \"set\".wav"),
+            concat!(
+                "FILE \"my 'set'.wav\" WAVE\n",
+                "  TRACK 01 AUDIO\n    TITLE \"Say 'hi' \\o/\"\n    INDEX 01 00:00:00\n",
+                "  TRACK 02 AUDIO\n    TITLE \"Unknown\"\n    INDEX 01 01:01:00\n",
+                "  TRACK 03 AUDIO\n    TITLE \"Tab end\"\n    INDEX 01 01:02:37\n",
+            )
+        );
+    }
+}
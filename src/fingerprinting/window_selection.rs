@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+
+use wasm_bindgen::prelude::*;
+
+use crate::fingerprinting::decoded_signature::DecodedSignature;
+use crate::fingerprinting::gain::mean_square_db;
+use crate::fingerprinting::signature_generator::{FrequencyBand, FrequencyPeak, SAMPLES_PER_FFT_PASS};
+
+/// Share of the score given to peak density, the rest goes to loudness
+const DENSITY_WEIGHT: f32 = 0.7;
+
+/// Windows at or below this RMS level score nothing for loudness
+const SILENCE_DB: f32 = -60.0;
+
+pub struct WindowOptions {
+    /// Length of each window
+    pub seconds: usize,
+    /// How many windows to return at most
+    pub count: usize,
+    /// Distance between the starts of candidate windows
+    pub hop_seconds: usize,
+}
+
+impl Default for WindowOptions {
+    fn default() -> Self {
+        WindowOptions {
+            seconds: 12,
+            count: 3,
+            hop_seconds: 1,
+        }
+    }
+}
+
+/// A window of the signal and how likely it is to be recognized
+#[derive(Debug, Clone, Copy)]
+pub struct WindowCandidate {
+    /// First sample of the window
+    pub start: usize,
+    /// Sample after the last one of the window
+    pub end: usize,
+    /// Peak density and loudness combined, between 0 and 1
+    pub score: f32,
+    pub loudness_db: f32,
+    pub peaks_per_second: f32,
+}
+
+/// Scores every window of `seconds` starting a hop apart by how many spectral peaks
+/// it holds and how loud it is, and returns the best `count` that don't overlap, best first.
+/// A signal shorter than one window is a single candidate.
+pub fn select_windows(
+    pcm: &[i16],
+    sample_rate: u32,
+    frequency_band_to_sound_peaks: &HashMap<FrequencyBand, Vec<FrequencyPeak>>,
+    options: &WindowOptions,
+) -> Vec<WindowCandidate> {
+    let sample_rate = sample_rate as usize;
+    let hop = options.hop_seconds.max(1) * sample_rate;
+    let window = (options.seconds.max(1) * sample_rate).min(pcm.len());
+    if window == 0 {
+        return Vec::new();
+    }
+
+    // Peaks and energy per hop, so each window sums a few buckets instead of its samples
+    let bucket_count = pcm.len().div_ceil(hop);
+    let mut bucket_peaks = vec![0u32; bucket_count];
+    for peak in frequency_band_to_sound_peaks.values().flatten() {
+        let bucket = peak.fft_pass_number as usize * SAMPLES_PER_FFT_PASS / hop;
+        if let Some(count) = bucket_peaks.get_mut(bucket) {
+            *count += 1;
+        }
+    }
+    let bucket_energy: Vec<f64> = pcm
+        .chunks(hop)
+        .map(|chunk| chunk.iter().map(|sample| (*sample as f64) * (*sample as f64)).sum())
+        .collect();
+
+    let mut candidates: Vec<WindowCandidate> = (0..=pcm.len() - window)
+        .step_by(hop)
+        .map(|start| {
+            let end = start + window;
+            let buckets = start / hop..end.div_ceil(hop);
+            let peaks: u32 = bucket_peaks[buckets.clone()].iter().sum();
+            let energy: f64 = bucket_energy[buckets].iter().sum();
+            WindowCandidate {
+                start,
+                end,
+                score: 0.0,
+                loudness_db: mean_square_db(energy / window as f64),
+                peaks_per_second: peaks as f32 * sample_rate as f32 / window as f32,
+            }
+        })
+        .collect();
+
+    let max_peaks_per_second = candidates.iter().fold(0f32, |max, candidate| max.max(candidate.peaks_per_second));
+    for candidate in candidates.iter_mut() {
+        let density = if max_peaks_per_second > 0.0 { candidate.peaks_per_second / max_peaks_per_second } else { 0.0 };
+        let loudness = ((candidate.loudness_db - SILENCE_DB) / -SILENCE_DB).clamp(0.0, 1.0);
+        candidate.score = DENSITY_WEIGHT * density + (1.0 - DENSITY_WEIGHT) * loudness;
+    }
+
+    // Best first, skipping windows overlapping one already taken
+    candidates.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.start.cmp(&b.start)));
+    let mut selected: Vec<WindowCandidate> = Vec::with_capacity(options.count);
+    for candidate in candidates {
+        if selected.len() == options.count {
+            break;
+        }
+        if selected.iter().all(|taken| candidate.end <= taken.start || candidate.start >= taken.end) {
+            selected.push(candidate);
+        }
+    }
+    selected
+}
+
+/// A signature of one of the windows picked by `select_windows`
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Clone)]
+pub struct ScoredSignature {
+    pub signature: DecodedSignature,
+    /// Peak density and loudness combined, between 0 and 1
+    pub score: f32,
+    #[wasm_bindgen(js_name = "startMs")]
+    pub start_ms: u32,
+    #[wasm_bindgen(js_name = "loudnessDb")]
+    pub loudness_db: f32,
+    #[wasm_bindgen(js_name = "peaksPerSecond")]
+    pub peaks_per_second: f32,
+}
+
+impl ScoredSignature {
+    pub fn new(signature: DecodedSignature, candidate: &WindowCandidate, sample_rate: u32) -> ScoredSignature {
+        ScoredSignature {
+            signature,
+            score: candidate.score,
+            start_ms: (candidate.start as u64 * 1000 / sample_rate as u64) as u32,
+            loudness_db: candidate.loudness_db,
+            peaks_per_second: candidate.peaks_per_second,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprinting::test_audio::{sine, to_i16};
+
+    const RATE: u32 = 16000;
+
+    /// `peaks_per_second` peaks in every second of each span of seconds
+    fn peaks(spans: &[(usize, usize, usize)]) -> HashMap<FrequencyBand, Vec<FrequencyPeak>> {
+        let passes_per_second = RATE as usize / SAMPLES_PER_FFT_PASS;
+        let frequency_peaks = spans
+            .iter()
+            .flat_map(|&(start, end, peaks_per_second)| {
+                (start * peaks_per_second..end * peaks_per_second).map(move |index| FrequencyPeak {
+                    fft_pass_number: (index * passes_per_second / peaks_per_second) as u32,
+                    peak_magnitude: 10000,
+                    corrected_peak_frequency_bin: 5000,
+                    sample_rate_hz: RATE,
+                })
+            })
+            .collect();
+        HashMap::from([(FrequencyBand::_520_1450, frequency_peaks)])
+    }
+
+    /// Seconds of a 1 KHz tone at each amplitude
+    fn pcm(amplitudes: &[(usize, f32)]) -> Vec<i16> {
+        let samples: Vec<f32> = amplitudes.iter().flat_map(|&(seconds, amplitude)| sine(1000.0, RATE, seconds * RATE as usize, amplitude)).collect();
+        to_i16(&samples)
+    }
+
+    #[test]
+    fn ranks_windows_by_peak_density_then_loudness() {
+        // Quiet and sparse, then loud and dense, then loud and half as dense
+        let pcm = pcm(&[(12, 0.01), (12, 0.5), (12, 0.5)]);
+        let peaks = peaks(&[(0, 12, 10), (12, 24, 50), (24, 36, 25)]);
+        let windows = select_windows(&pcm, RATE, &peaks, &WindowOptions::default());
+
+        let starts: Vec<usize> = windows.iter().map(|window| window.start / RATE as usize).collect();
+        assert_eq!(starts, [12, 24, 0]);
+        assert!(windows.iter().all(|window| window.end - window.start == 12 * RATE as usize));
+        assert!(windows.windows(2).all(|pair| pair[0].score > pair[1].score));
+
+        assert!((windows[0].peaks_per_second - 50.0).abs() < 0.01);
+        assert!((windows[0].loudness_db + 9.03).abs() < 0.05);
+        // Full density and 51 of the 60 dB above silence
+        assert!((windows[0].score - (0.7 + 0.3 * (60.0 - 9.03) / 60.0)).abs() < 0.01);
+        assert!((windows[2].loudness_db + 43.01).abs() < 0.05);
+    }
+
+    #[test]
+    fn picks_windows_that_dont_overlap() {
+        // Silent, so windows holding as many peaks tie exactly
+        let pcm = vec![0; 30 * RATE as usize];
+        let peaks = peaks(&[(0, 30, 20), (10, 13, 80)]);
+        let windows = select_windows(&pcm, RATE, &peaks, &WindowOptions { seconds: 5, count: 10, hop_seconds: 1 });
+
+        // The densest seconds come first, in the earliest window holding all of them, then
+        // windows of equal score earliest first, skipping those overlapping one already taken
+        let starts: Vec<usize> = windows.iter().map(|window| window.start / RATE as usize).collect();
+        assert_eq!(starts, [8, 0, 13, 18, 23]);
+    }
+
+    #[test]
+    fn takes_a_short_signal_whole() {
+        let pcm = pcm(&[(3, 0.5)]);
+        let windows = select_windows(&pcm, RATE, &peaks(&[(0, 3, 20)]), &WindowOptions::default());
+        assert_eq!(windows.len(), 1);
+        assert_eq!((windows[0].start, windows[0].end), (0, pcm.len()));
+        assert!(select_windows(&[], RATE, &HashMap::new(), &WindowOptions::default()).is_empty());
+    }
+}
+
@@ -1,53 +1,492 @@
-mod fingerprinting;
+pub mod fingerprinting;
+use std::collections::HashMap;
 use std::error::Error;
 
 use wasm_bindgen::prelude::*;
-use fingerprinting::{samples_from_bytes::samples_from_bytes, decoded_signature::DecodedSignature};
-use console_error_panic_hook;
+use fingerprinting::{samples_from_bytes::{samples_from_bytes_observed, pcm_from_bytes, DecodeOptions, DecodeReport}, decoded_signature::{check_sample_rate, DecodedSignature, Recognition, SilenceOptions, DEFAULT_SAMPLE_RATE, SAMPLE_RATES}};
+use fingerprinting::downmix::Downmix;
+use fingerprinting::probe::{probe_bytes as probe, AudioProbe, FormatHint, TrackSelection};
+use fingerprinting::progress::{CancellationToken, DecodeObserver, Progress, ProgressStage};
+use fingerprinting::compare::{compare_signatures as compare, CompareOptions, SignatureComparison};
+use fingerprinting::sync::{sync_offset, SyncResult};
+use fingerprinting::skew::{compare_skewed_peaks, SkewOptions, SkewedComparison};
+use fingerprinting::signature_generator::{peaks_sample_rate, FrequencyBand, FrequencyPeak, SignatureGenerator};
+use fingerprinting::resample::{resample_mono_observed, ResampleQuality};
+use fingerprinting::gain::{quantize, Normalization};
+use fingerprinting::preprocess::PreprocessOptions;
+use fingerprinting::window_selection::{select_windows, ScoredSignature, WindowOptions};
+use fingerprinting::catalogue::Catalogue;
+use fingerprinting::streaming::StreamOptions;
+use fingerprinting::tracklist::{generate_tracklist, Tracklist, TracklistOptions};
+
+#[wasm_bindgen(typescript_custom_section)]
+const RECOGNIZE_OPTIONS: &'static str = r#"
+export interface RecognizeOptions {
+    onProgress?: (progress: { stage: "decoding" | "resampling" | "fingerprinting"; bytesRead: number; totalBytes: number; packetsDecoded: number; percent?: number }) => void;
+    signal?: AbortSignal;
+    track?: number | string | { id: number };
+    hint?: string;
+    tolerant?: boolean;
+    downmix?: "average" | "itu" | "mid" | "side" | "loudest" | "left" | "right" | "centre" | "center" | "lfe" | number;
+    sampleRate?: 8000 | 11025 | 16000 | 32000 | 44100 | 48000;
+    resampleQuality?: "fast" | "balanced" | "best";
+    preprocess?: { dcRemoval?: boolean; highPassHz?: number; noiseGateDb?: number; preEmphasis?: number };
+    normalize?: "none" | "peak" | "rms" | "agc";
+    dither?: boolean;
+    silence?: { thresholdDb?: number; minPeaksPerBand?: number; drop?: boolean };
+}
+
+export type StreamingOptions = Pick<RecognizeOptions, "track" | "hint" | "downmix" | "sampleRate">;
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "RecognizeOptions")]
+    pub type RecognizeOptions;
+
+    #[wasm_bindgen(typescript_type = "number | \"auto\"")]
+    pub type RecognizeOffset;
+
+    #[wasm_bindgen(typescript_type = "StreamingOptions")]
+    pub type StreamingOptions;
+}
+
+/// Where `recognizeBytes` samples from
+enum Offset {
+    Seconds(Option<usize>),
+    /// The windows `recognizeBestWindows` picks
+    Auto,
+}
 
 /// Recognizes an audio fingerprint fron song bytes and returns decoded signatures.
 /// # Arguments
 /// * `bytes` - Bytes of the song file
-/// * `offset` - When to start sampling from in seconds
+/// * `offset` - When to start sampling from in seconds, or `"auto"` for the best windows of the song, see `recognizeBytesWithReport`
 /// * `seconds` - Seconds to sample from offset
+/// * `options` - `{ onProgress, signal, track, hint, tolerant, downmix, sampleRate, resampleQuality, preprocess, normalize, dither, silence }`, see `recognizeBytesWithReport`
 #[wasm_bindgen(js_name = "recognizeBytes")]
-pub fn recognize_bytes(bytes: Vec<u8>, offset: Option<usize>, seconds: Option<usize>) -> Result<Vec<DecodedSignature>, JsValue> {
-    match signatures_from_bytes(bytes, offset, seconds) {
-        Ok(sig) => Ok(sig),
-        Err(e) => return Err(JsValue::from_str(&e.to_string()))
+pub fn recognize_bytes(bytes: Vec<u8>, offset: Option<RecognizeOffset>, seconds: Option<usize>, options: Option<RecognizeOptions>) -> Result<Vec<DecodedSignature>, JsValue> {
+    Ok(recognize_bytes_with_report(bytes, offset, seconds, options)?.signatures())
+}
+
+/// Recognizes an audio fingerprint fron song bytes and returns decoded signatures along with what was found while decoding.
+/// # Arguments
+/// * `bytes` - Bytes of the song file
+/// * `offset` - When to start sampling from in seconds. `"auto"` returns the signatures of the 3 windows of `seconds` most likely to be recognized, best first, as `recognizeBestWindows` does
+/// * `seconds` - Seconds to sample from offset
+/// * `options.onProgress` - Called after every decoded packet with `{ stage, bytesRead, totalBytes, packetsDecoded, percent }`, then every few seconds of audio resampled and fingerprinted
+/// * `options.signal` - `AbortSignal` (or any object with an `aborted` property) that stops decoding, resampling or fingerprinting when aborted, checked whenever progress is reported
+/// * `options.track` - Audio track to decode: its index, `{ id }`, a language tag or `"default"` (the first decodable audio track), defaults to the first decodable track
+/// * `options.hint` - File name, extension or MIME type of the song file, for data that is ambiguous or has no header
+/// * `options.tolerant` - Replace packets that fail to decode with silence and keep what was decoded of a damaged file, listing what was skipped in the report
+/// * `options.downmix` - How multichannel audio is mixed to mono: `"average"` (default), `"itu"`, `"mid"`, `"side"`, `"loudest"`, a channel position such as `"centre"` or a channel index
+/// * `options.sampleRate` - Rate signatures are generated at: 8000, 11025, 16000 (default), 32000, 44100 or 48000 Hz, such as 8000 for narrowband telephone audio
+/// * `options.resampleQuality` - `"fast"`, `"balanced"` or `"best"` (default), audio already at `sampleRate` is never resampled
+/// * `options.preprocess` - Filters applied in this order before normalization: `dcRemoval`, a `highPassHz` cutoff, a spectral noise gate passing what rises `noiseGateDb` above the noise floor, and a `preEmphasis` coefficient
+/// * `options.normalize` - Level adjustment before quantizing to 16 bits: `"none"` (default), `"peak"`, `"rms"` or `"agc"`
+/// * `options.dither` - Add TPDF dither when quantizing to 16 bits
+/// * `options.silence` - Signatures below `thresholdDb` RMS or averaging fewer than `minPeaksPerBand` peaks per band are flagged `isSilent`, and left out with `drop`
+#[wasm_bindgen(js_name = "recognizeBytesWithReport")]
+pub fn recognize_bytes_with_report(bytes: Vec<u8>, offset: Option<RecognizeOffset>, seconds: Option<usize>, options: Option<RecognizeOptions>) -> Result<Recognition, JsValue> {
+    let offset = offset_from_js(offset)?;
+    let (decode_options, on_progress, signal) = recognize_options_from_js(options)?;
+    let mut observer = js_observer(on_progress.as_ref(), signal.as_ref());
+    let result = match offset {
+        Offset::Seconds(offset) => signatures_from_bytes(bytes, offset, seconds, &decode_options, &mut observer),
+        Offset::Auto => {
+            let defaults = WindowOptions::default();
+            let window_options = WindowOptions { seconds: seconds.unwrap_or(defaults.seconds), ..defaults };
+            best_windows_from_bytes(bytes, &window_options, &decode_options, &mut observer)
+                .map(|(scored_signatures, report)| (scored_signatures.into_iter().map(|scored_signature| scored_signature.signature).collect(), report))
+        }
+    };
+    match result {
+        Ok((signatures, report)) => Ok(Recognition::new(signatures, report)),
+        Err(e) => Err(JsValue::from_str(&e.to_string()))
+    }
+}
+
+/// Scans the whole song for the windows most likely to be recognized, rather than
+/// starting at a fixed offset that may land on silence or a fade-in.
+/// # Arguments
+/// * `bytes` - Bytes of the song file
+/// * `count` - How many windows to return at most, defaults to 3
+/// * `seconds` - Length of each window in seconds, defaults to 12
+/// * `options` - Same as `recognizeBytesWithReport`
+#[wasm_bindgen(js_name = "recognizeBestWindows")]
+pub fn recognize_best_windows(bytes: Vec<u8>, count: Option<usize>, seconds: Option<usize>, options: Option<RecognizeOptions>) -> Result<Vec<ScoredSignature>, JsValue> {
+    let (decode_options, on_progress, signal) = recognize_options_from_js(options)?;
+    let mut observer = js_observer(on_progress.as_ref(), signal.as_ref());
+    let defaults = WindowOptions::default();
+    let window_options = WindowOptions {
+        seconds: seconds.unwrap_or(defaults.seconds),
+        count: count.unwrap_or(defaults.count),
+        ..defaults
+    };
+    match best_windows_from_bytes(bytes, &window_options, &decode_options, &mut observer) {
+        Ok((scored_signatures, _)) => Ok(scored_signatures),
+        Err(e) => Err(JsValue::from_str(&e.to_string()))
+    }
+}
+
+/// Reads the format, audio tracks and tags of a song file without decoding it.
+/// # Arguments
+/// * `bytes` - Bytes of the song file
+/// * `hint` - File name, extension or MIME type of the song file, for data that is ambiguous or has no header
+#[wasm_bindgen(js_name = "probeBytes")]
+pub fn probe_bytes(bytes: Vec<u8>, hint: Option<String>) -> Result<AudioProbe, JsValue> {
+    let hint = hint.map(|hint| FormatHint::parse(&hint)).unwrap_or_default();
+    probe(bytes, &hint).map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+/// Compares two signatures and returns how similar they are and at what offset.
+/// # Arguments
+/// * `a` - First signature, generated or parsed with `DecodedSignature.fromUri`
+/// * `b` - Second signature, generated or parsed with `DecodedSignature.fromUri`
+/// * `max_offset_ms` - Largest time offset between the signatures to consider
+/// * `frequency_tolerance_hz` - Largest frequency difference for two peaks to match, defaults to 10Hz
+///
+/// Both signatures must have been generated at the same sample rate.
+#[wasm_bindgen(js_name = "compareSignatures")]
+pub fn compare_signatures(a: &mut DecodedSignature, b: &mut DecodedSignature, max_offset_ms: Option<u32>, frequency_tolerance_hz: Option<f32>) -> Result<SignatureComparison, JsValue> {
+    let defaults = CompareOptions::default();
+    compare(a, b, &CompareOptions {
+        max_offset_ms,
+        frequency_tolerance_hz: frequency_tolerance_hz.unwrap_or(defaults.frequency_tolerance_hz),
+        ..defaults
+    })
+    .map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+/// Compares a signature against reference audio that may have been played faster or slower, with or without keylock.
+/// # Arguments
+/// * `signature` - Signature of the capture
+/// * `reference_bytes` - Bytes of the reference song file
+/// * `max_skew` - Largest speed or pitch change to search for as a fraction, defaults to 0.08 (±8%)
+///
+/// The reference is fingerprinted at the sample rate of the signature.
+#[wasm_bindgen(js_name = "compareSkewed")]
+pub fn compare_skewed(signature: &mut DecodedSignature, reference_bytes: Vec<u8>, max_skew: Option<f32>) -> Result<SkewedComparison, JsValue> {
+    let capture = signature.frequency_band_to_sound_peaks();
+    let sample_rate = peaks_sample_rate(capture).unwrap_or(DEFAULT_SAMPLE_RATE);
+    let reference = reference_peaks_at(reference_bytes, sample_rate).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    let defaults = SkewOptions::default();
+    compare_skewed_peaks(
+        capture,
+        &reference,
+        &SkewOptions {
+            max_skew: max_skew.unwrap_or(defaults.max_skew),
+            ..defaults
+        },
+    )
+    .map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+/// Finds the time offset between two recordings of the same content, refined below one FFT pass and with drift estimated over long recordings.
+/// # Arguments
+/// * `a_bytes` - Bytes of the reference recording
+/// * `b_bytes` - Bytes of the recording to synchronize against the reference
+/// * `max_offset_ms` - Largest offset to search either way, defaults to 5 minutes. The search grows with this range, so keep it small for hour-long recordings
+#[wasm_bindgen(js_name = "syncBytes")]
+pub fn sync_bytes(a_bytes: Vec<u8>, b_bytes: Vec<u8>, max_offset_ms: Option<u32>) -> Result<SyncResult, JsValue> {
+    let a = pcm_from_bytes(a_bytes).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    let b = pcm_from_bytes(b_bytes).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    sync_offset(&a, &b, max_offset_ms).map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+/// Identifies the tracks of a long mix or broadcast recording against a catalogue, returning a timeline of tracks and unknown gaps.
+/// # Arguments
+/// * `bytes` - Bytes of the recording
+/// * `catalogue` - Catalogue of the reference tracks
+/// * `min_matched_landmarks` - Minimum number of landmarks a 12 second window must match for its track to be identified, defaults to 10. Windows overlap, starting every 3 seconds
+#[wasm_bindgen(js_name = "tracklistBytes")]
+pub fn tracklist_bytes(bytes: Vec<u8>, catalogue: &Catalogue, min_matched_landmarks: Option<u32>) -> Result<Tracklist, JsValue> {
+    let (pcm, _) = pcm_from_bytes_with_options(bytes, None, Some(usize::MAX), &DecodeOptions::default(), &mut DecodeObserver::default()).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    let duration_ms = (pcm.len() as u64 * 1000 / DEFAULT_SAMPLE_RATE as u64) as u32;
+    let defaults = TracklistOptions::default();
+    generate_tracklist(&SignatureGenerator::frequency_band_to_sound_peaks(&pcm), duration_ms, catalogue.index(), &TracklistOptions {
+        min_matched_landmarks: min_matched_landmarks.unwrap_or(defaults.min_matched_landmarks),
+        ..defaults
+    })
+    .map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+/// Parses a JS offset: seconds or `"auto"`
+fn offset_from_js(offset: Option<RecognizeOffset>) -> Result<Offset, JsValue> {
+    let offset: JsValue = offset.map_or(JsValue::UNDEFINED, Into::into);
+    if offset.is_undefined() || offset.is_null() {
+        return Ok(Offset::Seconds(None));
+    }
+    if let Some(seconds) = offset.as_f64().filter(|seconds| *seconds >= 0.0) {
+        return Ok(Offset::Seconds(Some(seconds as usize)));
+    }
+    match offset.as_string().as_deref() {
+        Some("auto") => Ok(Offset::Auto),
+        _ => Err(JsValue::from_str("Invalid offset, expected seconds or \"auto\"")),
+    }
+}
+
+/// Splits `RecognizeOptions` into decode options and what the JS observer needs
+fn recognize_options_from_js(options: Option<RecognizeOptions>) -> Result<(DecodeOptions, Option<js_sys::Function>, Option<js_sys::Object>), JsValue> {
+    let options: JsValue = options.map_or(JsValue::UNDEFINED, Into::into);
+    let get = |key: &str| -> Result<JsValue, JsValue> {
+        if options.is_undefined() || options.is_null() {
+            return Ok(JsValue::UNDEFINED);
+        }
+        js_sys::Reflect::get(&options, &key.into())
+    };
+    let on_progress = get("onProgress")?.dyn_into::<js_sys::Function>().ok();
+    let signal = get("signal")?.dyn_into::<js_sys::Object>().ok();
+    let decode_options = DecodeOptions {
+        track: track_from_js(&get("track")?)?,
+        hint: get("hint")?.as_string().map(|hint| FormatHint::parse(&hint)).unwrap_or_default(),
+        tolerant: get("tolerant")?.is_truthy(),
+        downmix: downmix_from_js(&get("downmix")?)?,
+        sample_rate: sample_rate_from_js(&get("sampleRate")?)?,
+        resample_quality: match get("resampleQuality")?.as_string() {
+            Some(quality) => ResampleQuality::parse(&quality).ok_or_else(|| JsValue::from_str("Invalid resampleQuality, expected \"fast\", \"balanced\" or \"best\""))?,
+            None => ResampleQuality::default(),
+        },
+        filters: preprocess_from_js(&get("preprocess")?)?.chain(),
+        normalization: match get("normalize")?.as_string() {
+            Some(normalization) => Normalization::parse(&normalization).ok_or_else(|| JsValue::from_str("Invalid normalize, expected \"none\", \"peak\", \"rms\" or \"agc\""))?,
+            None => Normalization::default(),
+        },
+        dither: get("dither")?.is_truthy(),
+        silence: silence_from_js(&get("silence")?)?,
+    };
+
+    Ok((decode_options, on_progress, signal))
+}
+
+/// Parses a JS signature sample rate, one of `SAMPLE_RATES`
+fn sample_rate_from_js(sample_rate: &JsValue) -> Result<Option<u32>, JsValue> {
+    match sample_rate.as_f64() {
+        Some(sample_rate) if sample_rate.fract() != 0.0 || !SAMPLE_RATES.contains(&(sample_rate as u32)) => {
+            Err(JsValue::from_str(&format!("Unsupported sample rate {} Hz, expected one of {:?}", sample_rate, SAMPLE_RATES)))
+        }
+        sample_rate => Ok(sample_rate.map(|sample_rate| sample_rate as u32)),
+    }
+}
+
+/// Parses a JS track selection: an index, `{ id }`, a language tag or `"default"`
+fn track_from_js(track: &JsValue) -> Result<TrackSelection, JsValue> {
+    if track.is_undefined() || track.is_null() {
+        return Ok(TrackSelection::FirstDecodable);
+    }
+    if let Some(index) = track.as_f64() {
+        if index < 0.0 || index.fract() != 0.0 {
+            return Err(JsValue::from_str("Invalid track index, expected a non-negative integer"));
+        }
+        return Ok(TrackSelection::Index(index as usize));
+    }
+    if let Some(language) = track.as_string() {
+        return Ok(match language.as_str() {
+            "default" => TrackSelection::Default,
+            _ => TrackSelection::Language(language),
+        });
+    }
+    match js_sys::Reflect::get(track, &"id".into())?.as_f64() {
+        Some(id) if id >= 0.0 && id.fract() == 0.0 && id <= u32::MAX as f64 => Ok(TrackSelection::Id(id as u32)),
+        Some(_) => Err(JsValue::from_str("Invalid track id, expected a non-negative 32 bit integer")),
+        None => Err(JsValue::from_str("Invalid track, expected an index, { id }, a language tag or \"default\"")),
+    }
+}
+
+/// Parses `StreamingOptions`, or a bare downmix as `StreamingRecognizer` used to take
+pub(crate) fn stream_options_from_js(options: Option<StreamingOptions>) -> Result<StreamOptions, JsValue> {
+    let options: JsValue = options.map_or(JsValue::UNDEFINED, Into::into);
+    if !options.is_object() {
+        return Ok(StreamOptions { downmix: downmix_from_js(&options)?, ..StreamOptions::default() });
+    }
+    let get = |key: &str| js_sys::Reflect::get(&options, &key.into());
+    Ok(StreamOptions {
+        track: track_from_js(&get("track")?)?,
+        hint: get("hint")?.as_string().map(|hint| FormatHint::parse(&hint)).unwrap_or_default(),
+        downmix: downmix_from_js(&get("downmix")?)?,
+        sample_rate: sample_rate_from_js(&get("sampleRate")?)?,
+    })
+}
+
+fn downmix_from_js(downmix: &JsValue) -> Result<Downmix, JsValue> {
+    if downmix.is_undefined() || downmix.is_null() {
+        return Ok(Downmix::Average);
+    }
+    if let Some(index) = downmix.as_f64() {
+        if index < 0.0 || index.fract() != 0.0 {
+            return Err(JsValue::from_str("Invalid downmix channel index, expected a non-negative integer"));
+        }
+        return Ok(Downmix::Index(index as usize));
+    }
+    downmix
+        .as_string()
+        .and_then(|downmix| Downmix::parse(&downmix))
+        .ok_or_else(|| JsValue::from_str("Invalid downmix, expected \"average\", \"itu\", \"mid\", \"side\", \"loudest\", a channel position or a channel index"))
+}
+
+fn preprocess_from_js(preprocess: &JsValue) -> Result<PreprocessOptions, JsValue> {
+    if preprocess.is_undefined() || preprocess.is_null() {
+        return Ok(PreprocessOptions::default());
     }
+    let get = |key: &str| js_sys::Reflect::get(preprocess, &key.into());
+    Ok(PreprocessOptions {
+        dc_removal: get("dcRemoval")?.is_truthy(),
+        high_pass_hz: get("highPassHz")?.as_f64().map(|hz| hz as f32),
+        noise_gate_db: get("noiseGateDb")?.as_f64().map(|db| db as f32),
+        pre_emphasis: get("preEmphasis")?.as_f64().map(|coefficient| coefficient as f32),
+    })
 }
 
-fn signatures_from_bytes(bytes: Vec<u8>, offset: Option<usize>, seconds: Option<usize>) -> Result<Vec<DecodedSignature>, Box<dyn Error>> {
+fn silence_from_js(silence: &JsValue) -> Result<SilenceOptions, JsValue> {
+    let defaults = SilenceOptions::default();
+    if silence.is_undefined() || silence.is_null() {
+        return Ok(defaults);
+    }
+    let get = |key: &str| js_sys::Reflect::get(silence, &key.into());
+    Ok(SilenceOptions {
+        threshold_db: get("thresholdDb")?.as_f64().map_or(defaults.threshold_db, |db| db as f32),
+        min_peaks_per_band: get("minPeaksPerBand")?.as_f64().map_or(defaults.min_peaks_per_band, |peaks| peaks as u32),
+        drop: get("drop")?.is_truthy(),
+    })
+}
+
+/// Reports progress to a JS callback, and cancels once an `AbortSignal`-like object is aborted
+fn js_observer<'a>(on_progress: Option<&'a js_sys::Function>, signal: Option<&'a js_sys::Object>) -> DecodeObserver<'a> {
+    let is_aborted = move || {
+        signal.is_some_and(|signal| js_sys::Reflect::get(signal, &"aborted".into()).is_ok_and(|aborted| aborted.is_truthy()))
+    };
+    let cancellation = CancellationToken::new();
+    if is_aborted() {
+        cancellation.cancel();
+    }
+    let token = cancellation.clone();
+    DecodeObserver::new(
+        Some(Box::new(move |progress: &Progress| {
+            if let Some(on_progress) = on_progress {
+                let object = js_sys::Object::new();
+                let _ = js_sys::Reflect::set(&object, &"stage".into(), &progress.stage.name().into());
+                let _ = js_sys::Reflect::set(&object, &"bytesRead".into(), &(progress.bytes_read as f64).into());
+                let _ = js_sys::Reflect::set(&object, &"totalBytes".into(), &(progress.total_bytes as f64).into());
+                let _ = js_sys::Reflect::set(&object, &"packetsDecoded".into(), &(progress.packets_decoded as f64).into());
+                let _ = js_sys::Reflect::set(&object, &"percent".into(), &progress.percent.into());
+                // A callback that throws stops decoding, like an abort
+                if on_progress.call1(&JsValue::NULL, &object).is_err() {
+                    token.cancel();
+                }
+            }
+            // JS only runs during the callback, which is also called while resampling and
+            // fingerprinting, so that is the only time the signal can have been aborted
+            if is_aborted() {
+                token.cancel();
+            }
+        })),
+        cancellation,
+    )
+}
+
+/// Decodes the song and turns the range into mono PCM at the signature rate, as the options ask
+fn pcm_from_bytes_with_options(
+    bytes: Vec<u8>,
+    offset: Option<usize>,
+    seconds: Option<usize>,
+    options: &DecodeOptions,
+    observer: &mut DecodeObserver,
+) -> Result<(Vec<i16>, DecodeReport), Box<dyn Error>> {
+    let target_rate = check_sample_rate(options.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE))?;
     let offset_seconds = offset.unwrap_or(0);
-    let (signal_spec, samples) = samples_from_bytes(bytes, offset_seconds + seconds.unwrap_or(12))?;
+    let (signal_spec, samples, mut report) = samples_from_bytes_observed(bytes, offset_seconds.saturating_add(seconds.unwrap_or(12)), options, observer)?;
 
     let sample_rate = signal_spec.rate;
-    let num_channels = signal_spec.channels.count() as usize;
+    let num_channels = signal_spec.channels.count();
+    let offset_samples = offset_seconds.saturating_mul(sample_rate as usize * num_channels).min(samples.len());
 
-    let sample_ratio = sample_rate  as usize * num_channels;
+    // Mix down and resample the whole range once, then drop the decoded samples before slicing
+    let mono_samples = options.downmix.apply(&samples[offset_samples..], signal_spec.channels)?;
+    drop(samples);
+    let mut resampled_samples = resample_mono_observed(sample_rate, 1, &mono_samples, target_rate, options.resample_quality, |done| {
+        observer.report_stage(ProgressStage::Resampling, done * 100.0)
+    })
+    .ok_or_else(CancellationToken::error)?;
+    drop(mono_samples);
+    options.filters.process(&mut resampled_samples, target_rate);
+    report.gain_db = options.normalization.apply(&mut resampled_samples, target_rate);
+    let (pcm, clipped_samples) = quantize(&resampled_samples, options.dither);
+    report.clipped_samples = clipped_samples;
+    Ok((pcm, report))
+}
 
-    let offset_samples = offset_seconds * sample_ratio;
-    let _12s_samples = sample_ratio * 12;
-    let samples_len = samples.len();
+fn signatures_from_bytes(
+    bytes: Vec<u8>,
+    offset: Option<usize>,
+    seconds: Option<usize>,
+    options: &DecodeOptions,
+    observer: &mut DecodeObserver,
+) -> Result<(Vec<DecodedSignature>, DecodeReport), Box<dyn Error>> {
+    let (pcm, mut report) = pcm_from_bytes_with_options(bytes, offset, seconds, options, observer)?;
 
-    // Calculate the number of slices needed, adjusting start index by the offset
-    let num_slices = ((samples_len.saturating_sub(offset_samples) + _12s_samples - 1) / _12s_samples).max(1);
-    let mut decoded_signatures = Vec::with_capacity(num_slices);
-    if num_slices == 1 {
-        let samples_slice = &samples[offset_samples..];
-        decoded_signatures.push(DecodedSignature::new(samples_slice.into(), sample_rate, num_channels));
+    let sample_rate = options.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE);
+    let _12s_samples = sample_rate as usize * 12;
+    let mut decoded_signatures = if pcm.len() <= _12s_samples {
+        vec![DecodedSignature::from_pcm(pcm, sample_rate)]
     } else {
-        let mut start_index = offset_samples;
-        while start_index < samples_len {
-            let end_index = (start_index + _12s_samples).min(samples_len);
-            let samples_slice = &samples[start_index..end_index];
-            decoded_signatures.push(DecodedSignature::new(samples_slice.into(), sample_rate, num_channels));
-            start_index = end_index;
+        pcm.chunks(_12s_samples)
+            .map(|samples_slice| DecodedSignature::from_pcm(samples_slice.to_vec(), sample_rate))
+            .collect()
+    };
+
+    // Generate the peaks up front so the FFT work reports progress and can be cancelled too
+    let signature_count = decoded_signatures.len() as f32;
+    for (index, decoded_signature) in decoded_signatures.iter_mut().enumerate() {
+        let on_progress = |done: f32| observer.report_stage(ProgressStage::Fingerprinting, (index as f32 + done) / signature_count * 100.0);
+        if !decoded_signature.generate_peaks(on_progress) {
+            return Err(CancellationToken::error().into());
+        }
+        if decoded_signature.mark_silence(&options.silence) {
+            report.silent_segments += 1;
         }
     }
+    if options.silence.drop {
+        decoded_signatures.retain_mut(|decoded_signature| !decoded_signature.is_silent());
+    }
+    Ok((decoded_signatures, report))
+}
+
+/// Peaks of a whole reference song file, found at `sample_rate` so they line up with a signature's
+fn reference_peaks_at(bytes: Vec<u8>, sample_rate: u32) -> Result<HashMap<FrequencyBand, Vec<FrequencyPeak>>, Box<dyn Error>> {
+    let options = DecodeOptions { sample_rate: Some(sample_rate), ..DecodeOptions::default() };
+    let (pcm, _) = pcm_from_bytes_with_options(bytes, None, Some(usize::MAX), &options, &mut DecodeObserver::default())?;
+    if pcm.is_empty() {
+        return Err("No audio decoded from the reference".into());
+    }
+    Ok(SignatureGenerator::frequency_band_to_sound_peaks_at(&pcm, sample_rate, |_| true).unwrap())
+}
+
+fn best_windows_from_bytes(
+    bytes: Vec<u8>,
+    window_options: &WindowOptions,
+    options: &DecodeOptions,
+    observer: &mut DecodeObserver,
+) -> Result<(Vec<ScoredSignature>, DecodeReport), Box<dyn Error>> {
+    let (pcm, report) = pcm_from_bytes_with_options(bytes, None, Some(usize::MAX), options, observer)?;
+    let sample_rate = options.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE);
+    // Scoring the whole song is the first half of fingerprinting, the selected windows the second
+    let frequency_band_to_sound_peaks = SignatureGenerator::frequency_band_to_sound_peaks_at(&pcm, sample_rate, |done| {
+        observer.report_stage(ProgressStage::Fingerprinting, done * 50.0)
+    })
+    .ok_or_else(CancellationToken::error)?;
 
-    Ok(decoded_signatures)
+    let candidates = select_windows(&pcm, sample_rate, &frequency_band_to_sound_peaks, window_options);
+    let candidate_count = candidates.len() as f32;
+    let mut scored_signatures = Vec::new();
+    for (index, candidate) in candidates.into_iter().enumerate() {
+        let mut signature = DecodedSignature::from_pcm(pcm[candidate.start..candidate.end].to_vec(), sample_rate);
+        let on_progress = |done: f32| observer.report_stage(ProgressStage::Fingerprinting, 50.0 + (index as f32 + done) / candidate_count * 50.0);
+        if !signature.generate_peaks(on_progress) {
+            return Err(CancellationToken::error().into());
+        }
+        scored_signatures.push(ScoredSignature::new(signature, &candidate, sample_rate));
+    }
+    Ok((scored_signatures, report))
 }
 
 #[wasm_bindgen(start)]